@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN auth_failures INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN auth_failure_notified BOOLEAN NOT NULL DEFAULT 0;
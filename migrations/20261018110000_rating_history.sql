@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS rating_history 
+(
+    id integer primary key, 
+    user_id INTEGER, 
+    subject_name TEXT,
+    component TEXT,
+    old_value REAL,
+    new_value REAL,
+    changed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS rating_history_user_subject ON rating_history (user_id, subject_name);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS update_cycles 
+(
+    id integer primary key, 
+    finished_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    succeeded INTEGER NOT NULL,
+    failed INTEGER NOT NULL,
+    auth_failed INTEGER NOT NULL,
+    parse_failed INTEGER NOT NULL
+);
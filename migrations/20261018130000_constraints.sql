@@ -0,0 +1,49 @@
+-- SQLite can't add constraints to existing tables, so both tables are rebuilt.
+-- Renaming first keeps the foreign key of the new rating table pointing at the new users table.
+ALTER TABLE users RENAME TO users_old;
+ALTER TABLE rating RENAME TO rating_old;
+
+CREATE TABLE users 
+(
+    id integer primary key, 
+    chat_id INTEGER NOT NULL UNIQUE, 
+    username TEXT NOT NULL DEFAULT '', 
+    pwd TEXT NOT NULL DEFAULT '',
+    semester INTEGER NOT NULL DEFAULT 0,
+    auth_failures INTEGER NOT NULL DEFAULT 0,
+    auth_failure_notified BOOLEAN NOT NULL DEFAULT 0
+);
+
+-- Of duplicate users the oldest row is kept, it's the one lookups by chat_id have been returning
+INSERT INTO users (id, chat_id, username, pwd, semester, auth_failures, auth_failure_notified)
+SELECT id, chat_id, coalesce(username, ''), coalesce(pwd, ''), coalesce(semester, 0), auth_failures, auth_failure_notified
+FROM users_old
+WHERE id IN (SELECT min(id) FROM users_old WHERE chat_id IS NOT NULL GROUP BY chat_id);
+
+CREATE TABLE rating 
+(
+    id integer primary key, 
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE, 
+    subject_name TEXT NOT NULL,
+    attendance REAL NOT NULL DEFAULT 0,
+    control REAL NOT NULL DEFAULT 0,
+    creative REAL NOT NULL DEFAULT 0,
+    test REAL NOT NULL DEFAULT 0,
+    UNIQUE (user_id, subject_name)
+);
+
+-- Duplicate subjects were always updated together, the latest row is as good as any
+INSERT INTO rating (id, user_id, subject_name, attendance, control, creative, test)
+SELECT id, user_id, subject_name, coalesce(attendance, 0), coalesce(control, 0), coalesce(creative, 0), coalesce(test, 0)
+FROM rating_old
+WHERE id IN (
+    SELECT max(id) FROM rating_old 
+    WHERE user_id IN (SELECT id FROM users) AND subject_name IS NOT NULL 
+    GROUP BY user_id, subject_name
+);
+
+DROP TABLE rating_old;
+DROP TABLE users_old;
+
+-- The unique constraints are backed by indexes, those cover the lookups by chat_id
+-- and by user_id (as the leading column), no separate indexes are needed.
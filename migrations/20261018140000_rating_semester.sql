@@ -0,0 +1,24 @@
+-- Ratings are kept per semester now, what's stored so far belongs to the current semester of the user.
+ALTER TABLE rating RENAME TO rating_old;
+
+CREATE TABLE rating 
+(
+    id integer primary key, 
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE, 
+    semester INTEGER NOT NULL,
+    subject_name TEXT NOT NULL,
+    attendance REAL NOT NULL DEFAULT 0,
+    control REAL NOT NULL DEFAULT 0,
+    creative REAL NOT NULL DEFAULT 0,
+    test REAL NOT NULL DEFAULT 0,
+    UNIQUE (user_id, semester, subject_name)
+);
+
+INSERT INTO rating (id, user_id, semester, subject_name, attendance, control, creative, test)
+SELECT rating_old.id, user_id, users.semester, subject_name, attendance, control, creative, test
+FROM rating_old JOIN users ON users.id = rating_old.user_id;
+
+DROP TABLE rating_old;
+
+ALTER TABLE rating_history ADD COLUMN semester INTEGER;
+UPDATE rating_history SET semester = (SELECT semester FROM users WHERE users.id = rating_history.user_id);
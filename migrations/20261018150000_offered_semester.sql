@@ -0,0 +1,2 @@
+-- The newest semester the user has been offered to switch to, so the offer is sent once
+ALTER TABLE users ADD COLUMN offered_semester INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- Users who blocked the bot or deleted their account can't be messaged, they aren't polled
+-- until they write to the bot again.
+ALTER TABLE users ADD COLUMN active BOOLEAN NOT NULL DEFAULT 1;
@@ -0,0 +1,17 @@
+-- Outgoing messages, written in the same transaction as the change they are about
+-- so that nothing is lost between saving a rating and telling the user.
+-- pending rows are sent and marked sent, rows that keep failing end up dead.
+CREATE TABLE notifications
+(
+    id integer primary key,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    message TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'sent', 'dead')),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    -- unix time, pending rows aren't sent before it
+    next_attempt_at INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    sent_at TIMESTAMP
+);
+CREATE INDEX notifications_status ON notifications (status, next_attempt_at);
@@ -0,0 +1,14 @@
+-- Notification preferences, users without a row get the defaults.
+-- Quiet hours are in Moscow time, last_digest_at is unix time.
+CREATE TABLE preferences
+(
+    user_id INTEGER PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    notify_attendance BOOLEAN NOT NULL DEFAULT 1,
+    notify_creative BOOLEAN NOT NULL DEFAULT 1,
+    notify_control BOOLEAN NOT NULL DEFAULT 1,
+    notify_test BOOLEAN NOT NULL DEFAULT 1,
+    quiet_start INTEGER,
+    quiet_end INTEGER,
+    delivery TEXT NOT NULL DEFAULT 'instant',
+    last_digest_at INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,3 @@
+-- When digests go out, the hour in Moscow time and for weekly digests the weekday (0 is Monday).
+ALTER TABLE preferences ADD COLUMN digest_hour INTEGER NOT NULL DEFAULT 20;
+ALTER TABLE preferences ADD COLUMN digest_weekday INTEGER NOT NULL DEFAULT 6;
@@ -0,0 +1,3 @@
+-- History is looked up within a semester
+DROP INDEX IF EXISTS rating_history_user_subject;
+CREATE INDEX IF NOT EXISTS rating_history_user_semester_subject ON rating_history (user_id, semester, subject_name);
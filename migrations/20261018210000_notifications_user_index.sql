@@ -0,0 +1,2 @@
+-- Due notifications are checked for earlier pending ones of the same user
+CREATE INDEX IF NOT EXISTS notifications_user_status ON notifications (user_id, status);
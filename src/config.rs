@@ -0,0 +1,302 @@
+use crate::limit::ScrapeLimits;
+use crate::rea;
+use serde::Deserialize;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use teloxide::types::UserId;
+
+pub(crate) const CONFIG_VAR: &str = "DANKE_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "danke.toml";
+
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    Read { path: PathBuf, err: std::io::Error },
+    Parse(toml::de::Error),
+    Missing(&'static str),
+    Invalid { key: &'static str, reason: String }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read { path, err } => write!(f, "couldn't read config file {}: {}", path.display(), err),
+            ConfigError::Parse(err) => write!(f, "couldn't parse config file: {}", err),
+            ConfigError::Missing(key) => write!(f, "`{}` is missing from the config", key),
+            ConfigError::Invalid { key, reason } => write!(f, "`{}` is invalid: {}", key, reason)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// The config file as written, everything is optional here so that
+// env variables can fill in the gaps before validation.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct File {
+    maintainers: Option<Vec<u64>>,
+    log_level: Option<String>,
+    #[serde(default)]
+    database: DatabaseFile,
+    #[serde(default)]
+    updates: UpdatesFile,
+    #[serde(default)]
+    scraper: ScraperFile,
+    #[serde(default)]
+    portal: PortalFile,
+    #[serde(default)]
+    semesters: SemestersFile
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct DatabaseFile {
+    url: Option<String>,
+    max_connections: Option<u32>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct UpdatesFile {
+    interval_secs: Option<u64>,
+    failed_interval_secs: Option<u64>,
+    max_auth_failures: Option<i64>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ScraperFile {
+    max_concurrent: Option<usize>,
+    requests_per_sec: Option<u32>,
+    max_retries: Option<u32>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct PortalFile {
+    url: Option<String>,
+    mock_dir: Option<PathBuf>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct SemestersFile {
+    min: Option<u8>,
+    max: Option<u8>
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct UpdateSettings {
+    pub(crate) interval_secs: u64,
+    pub(crate) failed_interval_secs: u64,
+    pub(crate) max_auth_failures: i64
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum PortalSettings {
+    Rea { url: String },
+    // A directory of recorded rating pages, for staging
+    Mock { dir: PathBuf }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Settings {
+    pub(crate) maintainers: Vec<UserId>,
+    pub(crate) log_level: log::LevelFilter,
+    pub(crate) db_url: String,
+    pub(crate) max_connections: u32,
+    pub(crate) updates: UpdateSettings,
+    pub(crate) scrape_limits: ScrapeLimits,
+    pub(crate) portal: PortalSettings,
+    // Semesters users are allowed to pick
+    pub(crate) semesters: RangeInclusive<u8>
+}
+
+impl Settings {
+    // Reads the file from $DANKE_CONFIG (danke.toml by default) and applies env overrides on top.
+    // The default file may be absent when everything required comes from the environment.
+    pub(crate) fn load() -> Result<Settings, ConfigError> {
+        let path = std::env::var(CONFIG_VAR).ok();
+        let text = match &path {
+            Some(path) => read(PathBuf::from(path))?,
+            None => match read(PathBuf::from(DEFAULT_CONFIG_PATH)) {
+                Err(ConfigError::Read { err, .. }) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+                res => res?
+            }
+        };
+
+        let mut file: File = toml::from_str(&text).map_err(ConfigError::Parse)?;
+        file.apply_env(|var| std::env::var(var).ok())?;
+        file.validate()
+    }
+
+    #[cfg(test)]
+    fn from_toml(text: &str) -> Result<Settings, ConfigError> {
+        toml::from_str::<File>(text).map_err(ConfigError::Parse)?.validate()
+    }
+}
+
+fn read(path: PathBuf) -> Result<String, ConfigError> {
+    std::fs::read_to_string(&path).map_err(|err| ConfigError::Read { path, err })
+}
+
+fn positive<T: PartialOrd + Default>(key: &'static str, value: T) -> Result<T, ConfigError> {
+    if value <= T::default() {
+        return Err(ConfigError::Invalid { key, reason: "must be greater than zero".to_string() });
+    }
+    Ok(value)
+}
+
+impl File {
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        if let Some(maintainers) = var("DANKE_MAINTAINERS") {
+            let maintainers = maintainers.split(',')
+                .map(|id| id.trim().parse::<u64>())
+                .collect::<Result<Vec<u64>, _>>()
+                .map_err(|err| ConfigError::Invalid { key: "DANKE_MAINTAINERS", reason: err.to_string() })?;
+            self.maintainers = Some(maintainers);
+        }
+        if let Some(log_level) = var("DANKE_LOG_LEVEL") {
+            self.log_level = Some(log_level);
+        }
+        if let Some(url) = var("DANKE_DATABASE_URL") {
+            self.database.url = Some(url);
+        }
+        // The portal comes either from a url or from recorded pages, an override replaces whichever the file has
+        if let Some(url) = var("DANKE_PORTAL_URL") {
+            self.portal.url = Some(url);
+            self.portal.mock_dir = None;
+        }
+        if let Some(dir) = var("DANKE_PORTAL_MOCK_DIR") {
+            self.portal.mock_dir = Some(PathBuf::from(dir));
+            self.portal.url = None;
+        }
+        Ok(())
+    }
+
+    fn validate(self) -> Result<Settings, ConfigError> {
+        let maintainers = self.maintainers.ok_or(ConfigError::Missing("maintainers"))?;
+        if maintainers.is_empty() {
+            return Err(ConfigError::Invalid { key: "maintainers", reason: "at least one maintainer is needed".to_string() });
+        }
+
+        let log_level = self.log_level.unwrap_or("warn".to_string());
+        let log_level = log_level.parse::<log::LevelFilter>()
+            .map_err(|_| ConfigError::Invalid { key: "log_level", reason: format!("unknown level {:?}", log_level) })?;
+
+        let portal = match (self.portal.url, self.portal.mock_dir) {
+            (Some(_), Some(_)) => return Err(ConfigError::Invalid { key: "portal", reason: "set either url or mock_dir, not both".to_string() }),
+            (_, Some(dir)) => PortalSettings::Mock { dir },
+            (url, None) => PortalSettings::Rea { url: url.unwrap_or(rea::DEFAULT_BASE_URL.to_string()) }
+        };
+
+        let semesters = positive("semesters.min", self.semesters.min.unwrap_or(1))?..=self.semesters.max.unwrap_or(8);
+        if semesters.is_empty() {
+            return Err(ConfigError::Invalid { key: "semesters", reason: "max is less than min".to_string() });
+        }
+
+        Ok(Settings {
+            maintainers: maintainers.into_iter().map(UserId).collect(),
+            log_level,
+            db_url: self.database.url.ok_or(ConfigError::Missing("database.url"))?,
+            max_connections: positive("database.max_connections", self.database.max_connections.unwrap_or(10))?,
+            updates: UpdateSettings {
+                interval_secs: positive("updates.interval_secs", self.updates.interval_secs.unwrap_or(1200))?,
+                failed_interval_secs: positive("updates.failed_interval_secs", self.updates.failed_interval_secs.unwrap_or(600))?,
+                max_auth_failures: positive("updates.max_auth_failures", self.updates.max_auth_failures.unwrap_or(3))?
+            },
+            scrape_limits: ScrapeLimits {
+                max_concurrent: positive("scraper.max_concurrent", self.scraper.max_concurrent.unwrap_or(4))?,
+                requests_per_sec: positive("scraper.requests_per_sec", self.scraper.requests_per_sec.unwrap_or(2))?,
+                max_retries: self.scraper.max_retries.unwrap_or(3)
+            },
+            portal,
+            semesters
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL: &str = r#"
+        maintainers = [434585640]
+
+        [database]
+        url = "sqlite:danke.db"
+    "#;
+
+    #[test]
+    fn minimal_config_gets_defaults() {
+        let settings = Settings::from_toml(MINIMAL).unwrap();
+        assert_eq!(settings.maintainers, vec![UserId(434585640)]);
+        assert_eq!(settings.log_level, log::LevelFilter::Warn);
+        assert_eq!(settings.updates.interval_secs, 1200);
+        assert_eq!(settings.scrape_limits.max_concurrent, 4);
+        assert_eq!(settings.semesters, 1..=8);
+        assert!(matches!(settings.portal, PortalSettings::Rea { url } if url == rea::DEFAULT_BASE_URL));
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let settings = Settings::from_toml(include_str!("../danke.example.toml")).unwrap();
+        assert!(!settings.maintainers.is_empty());
+    }
+
+    #[test]
+    fn missing_keys_are_named() {
+        let err = Settings::from_toml("[database]\nurl = \"sqlite:danke.db\"").unwrap_err();
+        assert!(matches!(err, ConfigError::Missing("maintainers")));
+
+        let err = Settings::from_toml("maintainers = [1]").unwrap_err();
+        assert!(matches!(err, ConfigError::Missing("database.url")));
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let err = Settings::from_toml(&format!("{}\n[updates]\ninterval_secs = 0", MINIMAL)).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { key: "updates.interval_secs", .. }));
+
+        let err = Settings::from_toml(&format!("{}\n[semesters]\nmin = 5\nmax = 4", MINIMAL)).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { key: "semesters", .. }));
+
+        let err = Settings::from_toml(&format!("log_level = \"loud\"\n{}", MINIMAL)).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { key: "log_level", .. }));
+
+        let err = Settings::from_toml(&format!("{}\n[scraper]\nmax_concurent = 4", MINIMAL)).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse(_)));
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let mut file: File = toml::from_str(MINIMAL).unwrap();
+        file.apply_env(|var| match var {
+            "DANKE_MAINTAINERS" => Some("1, 2".to_string()),
+            "DANKE_PORTAL_MOCK_DIR" => Some("tests/fixtures".to_string()),
+            _ => None
+        }).unwrap();
+
+        let settings = file.validate().unwrap();
+        assert_eq!(settings.maintainers, vec![UserId(1), UserId(2)]);
+        assert!(matches!(settings.portal, PortalSettings::Mock { .. }));
+    }
+
+    #[test]
+    fn env_portal_replaces_example_portal() {
+        let example = include_str!("../danke.example.toml");
+        let mut file: File = toml::from_str(example).unwrap();
+        file.apply_env(|var| (var == "DANKE_PORTAL_MOCK_DIR").then(|| "tests/fixtures".to_string())).unwrap();
+        assert!(matches!(file.validate().unwrap().portal, PortalSettings::Mock { dir } if dir.to_str() == Some("tests/fixtures")));
+
+        let mut file: File = toml::from_str(&example.replace("# mock_dir", "mock_dir")).unwrap();
+        file.apply_env(|var| (var == "DANKE_PORTAL_URL").then(|| "http://localhost:8080".to_string())).unwrap();
+        assert!(matches!(file.validate().unwrap().portal, PortalSettings::Rea { url } if url == "http://localhost:8080"));
+    }
+
+    #[test]
+    fn example_mock_dir_can_be_uncommented() {
+        let example = include_str!("../danke.example.toml").replace("# mock_dir", "mock_dir");
+        assert!(matches!(Settings::from_toml(&example).unwrap().portal, PortalSettings::Mock { .. }));
+    }
+}
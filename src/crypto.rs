@@ -0,0 +1,107 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+// Everything written by `Cipher::encrypt` starts with this, so plaintext rows
+// left over from before encryption can be told apart from encrypted ones.
+const PREFIX: &str = "enc1:";
+const NONCE_LEN: usize = 12;
+
+pub(crate) const KEY_VAR: &str = "DANKE_SECRET_KEY";
+pub(crate) const NEW_KEY_VAR: &str = "DANKE_NEW_SECRET_KEY";
+
+#[derive(Clone)]
+pub(crate) struct Cipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub(crate) fn from_env(var: &str) -> Result<Cipher, String> {
+        let key = std::env::var(var).map_err(|_| format!("{} is not set", var))?;
+        Cipher::from_base64(&key).ok_or(format!("{} must be 32 base64 encoded bytes", var))
+    }
+
+    pub(crate) fn from_base64(key: &str) -> Option<Cipher> {
+        let key = STANDARD.decode(key.trim()).ok()?;
+        if key.len() != 32 {
+            return None;
+        }
+
+        Some(Cipher { cipher: ChaCha20Poly1305::new(Key::from_slice(&key)) })
+    }
+
+    pub(crate) fn generate_key() -> String {
+        STANDARD.encode(ChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    pub(crate) fn is_encrypted(stored: &str) -> bool {
+        stored.starts_with(PREFIX)
+    }
+
+    pub(crate) fn encrypt(&self, plaintext: &str) -> String {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher.encrypt(&nonce, plaintext.as_bytes())
+            .expect("chacha20poly1305 encryption is infallible for in-memory buffers");
+
+        let mut payload = nonce.to_vec();
+        payload.extend(ciphertext);
+        format!("{}{}", PREFIX, STANDARD.encode(payload))
+    }
+
+    pub(crate) fn decrypt(&self, stored: &str) -> Option<String> {
+        let payload = STANDARD.decode(stored.strip_prefix(PREFIX)?).ok()?;
+        if payload.len() < NONCE_LEN {
+            return None;
+        }
+
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        let plaintext = self.cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::test_cipher;
+
+    #[test]
+    fn encrypted_password_decrypts_back() {
+        let cipher = test_cipher();
+        let stored = cipher.encrypt("secret");
+        assert!(Cipher::is_encrypted(&stored));
+        assert!(!stored.contains("secret"));
+        assert_eq!(cipher.decrypt(&stored).as_deref(), Some("secret"));
+
+        // Every encryption gets its own nonce
+        assert_ne!(cipher.encrypt("secret"), stored);
+    }
+
+    #[test]
+    fn wrong_key_doesnt_decrypt() {
+        let stored = test_cipher().encrypt("secret");
+        assert_eq!(test_cipher().decrypt(&stored), None);
+    }
+
+    #[test]
+    fn tampered_ciphertext_doesnt_decrypt() {
+        let cipher = test_cipher();
+        let mut payload = STANDARD.decode(cipher.encrypt("secret").strip_prefix(PREFIX).unwrap()).unwrap();
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+
+        assert_eq!(cipher.decrypt(&format!("{}{}", PREFIX, STANDARD.encode(&payload))), None);
+        assert_eq!(cipher.decrypt(&format!("{}{}", PREFIX, STANDARD.encode(&payload[..NONCE_LEN - 1]))), None);
+        assert_eq!(cipher.decrypt("enc1:not base64"), None);
+        assert_eq!(cipher.decrypt("secret"), None);
+    }
+
+    #[test]
+    fn keys_must_be_32_bytes() {
+        assert!(Cipher::from_base64(&STANDARD.encode([0u8; 16])).is_none());
+        assert!(Cipher::from_base64("not base64").is_none());
+        assert!(Cipher::from_base64(&STANDARD.encode([0u8; 32])).is_some());
+    }
+}
@@ -1,104 +1,683 @@
 use std::collections::HashMap;
+use std::str::FromStr;
+use crate::crypto;
+use crate::prefs;
 use crate::rating;
 
+#[derive(Debug)]
+pub(crate) enum DbError {
+    // The row isn't there, which callers usually want to tell apart from a broken database
+    NotFound,
+    // A stored password doesn't decrypt with the key it's supposed to be under
+    UndecryptablePassword { user_id: i64 },
+    Sqlx(sqlx::Error)
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::NotFound => write!(f, "not found"),
+            DbError::UndecryptablePassword { user_id } => write!(f, "couldn't decrypt password of user {}", user_id),
+            DbError::Sqlx(err) => write!(f, "database error ({})", err)
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::Sqlx(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<sqlx::Error> for DbError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => DbError::NotFound,
+            err => DbError::Sqlx(err)
+        }
+    }
+}
+
 #[derive(sqlx::FromRow, Debug, Clone)]
 pub(crate) struct User {
     pub(crate) id: i64,
     pub(crate) chat_id: i64,
     pub(crate) username: String,
     pub(crate) pwd: String,
-    pub(crate) semester: u8
+    pub(crate) semester: u8,
+    pub(crate) auth_failures: i64,
+    pub(crate) auth_failure_notified: bool
 }
 
-pub(crate) async fn get_users(conn: &sqlx::Pool<sqlx::Sqlite>) -> Option<Vec<User>> {
-    let users = sqlx::query_as::<_, User>("SELECT id, chat_id, username, pwd, semester FROM users")
-    .fetch_all(conn)
-    .await;
+pub(crate) async fn connect(db_url: &str, max_connections: u32) -> Result<sqlx::Pool<sqlx::Sqlite>, sqlx::Error> {
+    let options = sqlx::sqlite::SqliteConnectOptions::from_str(db_url)?
+        .create_if_missing(true);
 
-    if users.is_err() {
-        log::error!("Couldn't fetch users from db");
-        return None;
-    }
+    sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(max_connections)
+        .connect_with(options)
+        .await
+}
 
-    Some(users.unwrap())
+// Applies the migrations embedded at build time. A database migrated by a newer build
+// has versions this one doesn't know about and fails with VersionMissing.
+pub(crate) async fn migrate(conn: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::migrate::MigrateError> {
+    sqlx::migrate!().run(conn).await
+}
+
+pub(crate) async fn get_users(conn: &sqlx::Pool<sqlx::Sqlite>) -> Result<Vec<User>, DbError> {
+    let users = sqlx::query_as::<_, User>("SELECT id, chat_id, username, pwd, semester, auth_failures, auth_failure_notified FROM users")
+    .fetch_all(conn)
+    .await?;
+    Ok(users)
 }
 
-pub(crate) async fn get_user(conn: &sqlx::Pool<sqlx::Sqlite>, user_chat_id: i64) -> Option<User> {
-    let user = sqlx::query_as::<_, User>("SELECT id, chat_id, username, pwd, semester FROM users where chat_id = ?")
+// Users with credentials that still work and a chat that still receives messages.
+pub(crate) async fn get_pollable_users(conn: &sqlx::Pool<sqlx::Sqlite>, max_auth_failures: i64) -> Result<Vec<User>, DbError> {
+    let users = sqlx::query_as::<_, User>("SELECT id, chat_id, username, pwd, semester, auth_failures, auth_failure_notified FROM users 
+        where username <> '' and pwd <> '' and auth_failures < ? and active")
+    .bind(max_auth_failures)
+    .fetch_all(conn)
+    .await?;
+    Ok(users)
+}
+
+pub(crate) async fn find_user(conn: &sqlx::Pool<sqlx::Sqlite>, user_chat_id: i64) -> Result<User, DbError> {
+    let user = sqlx::query_as::<_, User>("SELECT id, chat_id, username, pwd, semester, auth_failures, auth_failure_notified FROM users where chat_id = ?")
     .bind(user_chat_id)
     .fetch_one(conn)
-    .await;
-
-    if user.is_err() {
-        log::info!("User with id {} not found, creating new", user_chat_id);
-        let user_id = sqlx::query!("INSERT into users (chat_id) values (?)", user_chat_id)
-            .execute(conn)
-            .await;
-
-        if user_id.is_err() { return None; }
-        
-        let user = User { 
-            id: user_id.unwrap().last_insert_rowid(), 
-            chat_id: user_chat_id,
-            username: "".to_string(), 
-            pwd: "".to_string(),
-            semester: 0
-        };
-        return Some(user);
+    .await?;
+    Ok(user)
+}
+
+// Registers the chat, a chat that is already registered gets its existing user back.
+pub(crate) async fn create_user(conn: &sqlx::Pool<sqlx::Sqlite>, user_chat_id: i64) -> Result<User, DbError> {
+    let inserted = sqlx::query!("INSERT INTO users (chat_id) values (?) ON CONFLICT (chat_id) DO NOTHING", user_chat_id)
+    .execute(conn)
+    .await?;
+    if inserted.rows_affected() > 0 {
+        log::info!("Registered user with chat id {}", user_chat_id);
     }
 
-    Some(user.unwrap())
+    find_user(conn, user_chat_id).await
 }
 
-pub(crate) async fn sync_user(conn: &sqlx::Pool<sqlx::Sqlite>, user: &User) -> Result<(), ()> {
-    let query_res = sqlx::query!("UPDATE users set username = ?, pwd = ?, semester = ? where id = ?", user.username, user.pwd, user.semester, user.id)
-    .execute(conn).await;
+// Fails with NotFound if the user was deleted in the meantime.
+pub(crate) async fn sync_user(conn: &sqlx::Pool<sqlx::Sqlite>, user: &User) -> Result<(), DbError> {
+    let res = sqlx::query!("UPDATE users set username = ?, pwd = ?, semester = ?, auth_failures = ?, auth_failure_notified = ? where id = ?", 
+        user.username, user.pwd, user.semester, user.auth_failures, user.auth_failure_notified, user.id)
+    .execute(conn).await?;
 
-    if let Err(err) = query_res {
-        log::error!("{}", err.to_string());
-        return Err(());
+    if res.rows_affected() == 0 {
+        return Err(DbError::NotFound);
     }
     Ok(())
 }
 
-pub(crate) async fn get_rating(conn: &sqlx::Pool<sqlx::Sqlite>, user: &User) -> Option<Vec<rating::Subject>> {
-    let user_rating = sqlx::query_as::<_, rating::Subject>("SELECT subject_name as name, attendance, control, creative, test FROM rating where user_id = ?")
+// Saves the semester picked for a user who hasn't set one.
+pub(crate) async fn set_semester(conn: &mut sqlx::SqliteConnection, user: &User, semester: u8) -> Result<(), DbError> {
+    sqlx::query!("UPDATE users set semester = ?, offered_semester = ? where id = ? and semester = 0", semester, semester, user.id)
+    .execute(conn).await?;
+    Ok(())
+}
+
+// Returns true only the first time the semester is offered to the user.
+pub(crate) async fn offer_semester(conn: &mut sqlx::SqliteConnection, user: &User, semester: u8) -> Result<bool, DbError> {
+    let res = sqlx::query!("UPDATE users set offered_semester = ? where id = ? and offered_semester < ?", semester, user.id, semester)
+    .execute(conn).await?;
+    Ok(res.rows_affected() == 1)
+}
+
+pub(crate) async fn record_auth_failure(conn: &sqlx::Pool<sqlx::Sqlite>, user: &User) -> Result<(), DbError> {
+    sqlx::query!("UPDATE users set auth_failures = auth_failures + 1 where id = ?", user.id)
+    .execute(conn).await?;
+    Ok(())
+}
+
+pub(crate) async fn reset_auth_failures(conn: &sqlx::Pool<sqlx::Sqlite>, user: &User) -> Result<(), DbError> {
+    sqlx::query!("UPDATE users set auth_failures = 0, auth_failure_notified = 0 where id = ?", user.id)
+    .execute(conn).await?;
+    Ok(())
+}
+
+// The chat blocked the bot or is gone, nothing is sent or polled for it anymore.
+pub(crate) async fn deactivate_user(conn: &sqlx::Pool<sqlx::Sqlite>, user_chat_id: i64) -> Result<(), DbError> {
+    sqlx::query!("UPDATE users set active = 0 where chat_id = ?", user_chat_id)
+    .execute(conn).await?;
+    Ok(())
+}
+
+// True if the chat was inactive and is back now.
+pub(crate) async fn activate_user(conn: &sqlx::Pool<sqlx::Sqlite>, user_chat_id: i64) -> Result<bool, DbError> {
+    let res = sqlx::query!("UPDATE users set active = 1 where chat_id = ? and not active", user_chat_id)
+    .execute(conn).await?;
+    Ok(res.rows_affected() > 0)
+}
+
+// Forgets the credentials, users without them aren't polled. The stored rating is kept.
+pub(crate) async fn logout(conn: &sqlx::Pool<sqlx::Sqlite>, user: &User) -> Result<(), DbError> {
+    sqlx::query!("UPDATE users set username = '', pwd = '', auth_failures = 0, auth_failure_notified = 0 where id = ?", user.id)
+    .execute(conn).await?;
+    Ok(())
+}
+
+// Removes the user with everything stored about them.
+pub(crate) async fn delete_user(conn: &sqlx::Pool<sqlx::Sqlite>, user: &User) -> Result<(), DbError> {
+    let mut tx = conn.begin().await?;
+
+    // rating_history has no foreign key, rating would cascade but is deleted explicitly all the same
+    sqlx::query!("DELETE FROM notifications where user_id = ?", user.id).execute(&mut tx).await?;
+    sqlx::query!("DELETE FROM preferences where user_id = ?", user.id).execute(&mut tx).await?;
+    sqlx::query!("DELETE FROM rating_history where user_id = ?", user.id).execute(&mut tx).await?;
+    sqlx::query!("DELETE FROM rating where user_id = ?", user.id).execute(&mut tx).await?;
+    sqlx::query!("DELETE FROM users where id = ?", user.id).execute(&mut tx).await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+// Users whose credentials stopped working and who haven't been told about it yet,
+// they are marked as notified right away so the message goes out only once.
+pub(crate) async fn take_auth_failure_notices(conn: &mut sqlx::SqliteConnection, max_auth_failures: i64) -> Result<Vec<User>, DbError> {
+    let users = sqlx::query_as::<_, User>("UPDATE users set auth_failure_notified = 1 where auth_failures >= ? and not auth_failure_notified 
+        RETURNING id, chat_id, username, pwd, semester, auth_failures, auth_failure_notified")
+    .bind(max_auth_failures)
+    .fetch_all(conn)
+    .await?;
+    Ok(users)
+}
+
+// NotFound means there's no rating for the semester yet.
+pub(crate) async fn get_rating(conn: &sqlx::Pool<sqlx::Sqlite>, user: &User, semester: u8) -> Result<Vec<rating::Subject>, DbError> {
+    let user_rating = sqlx::query_as::<_, rating::Subject>("SELECT subject_name as name, attendance, control, creative, test FROM rating where user_id = ? and semester = ? order by id")
         .bind(user.id)
+        .bind(semester)
         .fetch_all(conn)
-        .await;
+        .await?;
 
-    if let Err(err) = user_rating {
-        log::error!("{}", err.to_string());
-        return None;
-    }
-
-    let user_rating = user_rating.unwrap();
     if user_rating.is_empty() {
-        return None;
+        return Err(DbError::NotFound);
     }
-
-    Some(user_rating)
+    Ok(user_rating)
 }
 
-pub(crate) async fn get_rating_map(conn: &sqlx::Pool<sqlx::Sqlite>, user: &User) -> Option<HashMap<String, rating::Subject>> {
-    let user_rating = sqlx::query_as::<_, rating::Subject>("SELECT subject_name as name, attendance, control, creative, test FROM rating where user_id = ?")
+pub(crate) async fn get_rating_map(conn: &mut sqlx::SqliteConnection, user: &User, semester: u8) -> Result<HashMap<String, rating::Subject>, DbError> {
+    let user_rating = sqlx::query_as::<_, rating::Subject>("SELECT subject_name as name, attendance, control, creative, test FROM rating where user_id = ? and semester = ?")
         .bind(user.id)
+        .bind(semester)
         .fetch_all(conn)
-        .await;
-    if user_rating.is_err() { return None; }
+        .await?;
 
     let mut map: HashMap<String, rating::Subject> = HashMap::new(); 
-    for subject in user_rating.unwrap() {
+    for subject in user_rating {
         map.insert(subject.name.to_owned(), subject);
     }
 
-    Some(map)
+    Ok(map)
+}
+
+pub(crate) async fn upsert_subject(conn: &mut sqlx::SqliteConnection, user: &User, semester: u8, subject: &rating::Subject) -> Result<(), DbError> {
+    sqlx::query!("INSERT into rating (user_id, semester, subject_name, attendance, control, creative, test) values (?, ?, ?, ?, ?, ?, ?) 
+        ON CONFLICT (user_id, semester, subject_name) DO UPDATE SET attendance = excluded.attendance, control = excluded.control, creative = excluded.creative, test = excluded.test", 
+        user.id, semester, subject.name, subject.attendance, subject.control, subject.creative, subject.test)
+    .execute(conn).await?;
+    Ok(())
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub(crate) struct HistoryEntry {
+    pub(crate) component: String,
+    pub(crate) old_value: Option<f32>,
+    pub(crate) new_value: f32,
+    pub(crate) changed_at: String
+}
+
+// old_value is None for the first time a subject is seen.
+pub(crate) async fn insert_history(conn: &mut sqlx::SqliteConnection, user: &User, semester: u8, subject_name: &str, component: &str, old_value: Option<f32>, new_value: f32) -> Result<(), DbError> {
+    sqlx::query!("INSERT into rating_history (user_id, semester, subject_name, component, old_value, new_value) values (?, ?, ?, ?, ?, ?)", 
+        user.id, semester, subject_name, component, old_value, new_value)
+    .execute(conn).await?;
+    Ok(())
+}
+
+// changed_at is in Moscow time, the same as everything else users see.
+pub(crate) async fn get_history(conn: &sqlx::Pool<sqlx::Sqlite>, user: &User, semester: u8, subject_name: &str) -> Result<Vec<HistoryEntry>, DbError> {
+    let history = sqlx::query_as::<_, HistoryEntry>("SELECT component, old_value, new_value, datetime(changed_at, '+3 hours') as changed_at FROM rating_history 
+        where user_id = ? and semester = ? and subject_name = ? order by id")
+        .bind(user.id)
+        .bind(semester)
+        .bind(subject_name)
+        .fetch_all(conn)
+        .await?;
+    Ok(history)
+}
+
+pub(crate) async fn get_subject_names(conn: &sqlx::Pool<sqlx::Sqlite>, user: &User, semester: u8) -> Result<Vec<String>, DbError> {
+    let names = sqlx::query_scalar::<_, String>("SELECT distinct subject_name FROM rating_history where user_id = ? and semester = ?")
+        .bind(user.id)
+        .bind(semester)
+        .fetch_all(conn)
+        .await?;
+    Ok(names)
+}
+
+// Users without saved preferences get the defaults.
+pub(crate) async fn get_preferences(conn: impl sqlx::SqliteExecutor<'_>, user: &User) -> Result<prefs::Preferences, DbError> {
+    let preferences = sqlx::query_as::<_, prefs::Preferences>("SELECT notify_attendance as attendance, notify_creative as creative, notify_control as control, notify_test as test, 
+        quiet_start, quiet_end, delivery, last_digest_at, digest_hour, digest_weekday FROM preferences where user_id = ?")
+    .bind(user.id)
+    .fetch_optional(conn)
+    .await?;
+    Ok(preferences.unwrap_or_default())
+}
+
+pub(crate) async fn save_preferences(conn: impl sqlx::SqliteExecutor<'_>, user: &User, preferences: &prefs::Preferences) -> Result<(), DbError> {
+    sqlx::query("INSERT into preferences (user_id, notify_attendance, notify_creative, notify_control, notify_test, quiet_start, quiet_end, delivery, last_digest_at, digest_hour, digest_weekday) 
+        values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) 
+        ON CONFLICT (user_id) DO UPDATE SET notify_attendance = excluded.notify_attendance, notify_creative = excluded.notify_creative, 
+        notify_control = excluded.notify_control, notify_test = excluded.notify_test, quiet_start = excluded.quiet_start, quiet_end = excluded.quiet_end, 
+        delivery = excluded.delivery, last_digest_at = excluded.last_digest_at,
+        digest_hour = excluded.digest_hour, digest_weekday = excluded.digest_weekday")
+    .bind(user.id)
+    .bind(preferences.attendance)
+    .bind(preferences.creative)
+    .bind(preferences.control)
+    .bind(preferences.test)
+    .bind(preferences.quiet_start)
+    .bind(preferences.quiet_end)
+    .bind(preferences.delivery)
+    .bind(preferences.last_digest_at)
+    .bind(preferences.digest_hour)
+    .bind(preferences.digest_weekday)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+// Active users who get their changes in digests.
+pub(crate) async fn get_digest_users(conn: &sqlx::Pool<sqlx::Sqlite>) -> Result<Vec<User>, DbError> {
+    let users = sqlx::query_as::<_, User>("SELECT id, chat_id, username, pwd, semester, auth_failures, auth_failure_notified 
+        FROM users JOIN preferences ON preferences.user_id = users.id where preferences.delivery <> 'instant' and users.active")
+    .fetch_all(conn)
+    .await?;
+    Ok(users)
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub(crate) struct Change {
+    pub(crate) subject_name: String,
+    pub(crate) component: String,
+    pub(crate) old_value: f32,
+    pub(crate) new_value: f32
+}
+
+// Changes of already known subjects of the semester recorded after `since` (unix time), oldest first.
+pub(crate) async fn get_changes_since(conn: &mut sqlx::SqliteConnection, user: &User, semester: u8, since: i64) -> Result<Vec<Change>, DbError> {
+    let changes = sqlx::query_as::<_, Change>("SELECT subject_name, component, old_value, new_value FROM rating_history 
+        where user_id = ? and semester = ? and old_value is not null and changed_at > datetime(?, 'unixepoch') order by id")
+    .bind(user.id)
+    .bind(semester)
+    .bind(since)
+    .fetch_all(conn)
+    .await?;
+    Ok(changes)
+}
+
+#[derive(sqlx::FromRow, Debug, Default)]
+pub(crate) struct CycleSummary {
+    pub(crate) succeeded: i64,
+    pub(crate) failed: i64,
+    pub(crate) auth_failed: i64,
+    pub(crate) parse_failed: i64
+}
+
+impl CycleSummary {
+    pub(crate) fn total(&self) -> i64 {
+        self.succeeded + self.failed + self.auth_failed + self.parse_failed
+    }
+}
+
+impl std::fmt::Display for CycleSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} succeeded, {} failed, {} auth failed, {} parse failed", self.succeeded, self.failed, self.auth_failed, self.parse_failed)
+    }
+}
+
+pub(crate) async fn insert_cycle_summary(conn: &sqlx::Pool<sqlx::Sqlite>, summary: &CycleSummary) -> Result<(), DbError> {
+    sqlx::query!("INSERT into update_cycles (succeeded, failed, auth_failed, parse_failed) values (?, ?, ?, ?)", 
+        summary.succeeded, summary.failed, summary.auth_failed, summary.parse_failed)
+    .execute(conn).await?;
+    Ok(())
+}
+
+// NotFound before the first update cycle has finished.
+pub(crate) async fn get_last_cycle_summary(conn: &sqlx::Pool<sqlx::Sqlite>) -> Result<(String, CycleSummary), DbError> {
+    let (finished_at, succeeded, failed, auth_failed, parse_failed) = sqlx::query_as::<_, (String, i64, i64, i64, i64)>("SELECT finished_at, succeeded, failed, auth_failed, parse_failed FROM update_cycles order by id desc limit 1")
+        .fetch_one(conn)
+        .await?;
+
+    Ok((finished_at, CycleSummary { succeeded, failed, auth_failed, parse_failed }))
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub(crate) struct QueuedNotification {
+    pub(crate) id: i64,
+    pub(crate) chat_id: i64,
+    pub(crate) message: String,
+    pub(crate) attempts: i64
+}
+
+// Pass the transaction that saves whatever the message is about.
+// The notification isn't sent before `not_before` (unix time, 0 to send right away).
+pub(crate) async fn queue_notification(conn: &mut sqlx::SqliteConnection, user: &User, message: &str, not_before: i64) -> Result<(), DbError> {
+    sqlx::query!("INSERT into notifications (user_id, message, next_attempt_at) values (?, ?, ?)", user.id, message, not_before)
+    .execute(conn).await?;
+    Ok(())
 }
 
-pub(crate) async fn delete_rating(conn: &sqlx::Pool<sqlx::Sqlite>, user: &User) -> Result<(), ()> {
-    let query_res = sqlx::query!("delete from rating where user_id = ?", user.id)
-    .execute(conn).await;
+// Oldest pending notifications that are due, those of inactive users wait until they come back.
+// A user's notifications are sent in order, so they also wait behind an earlier one that isn't due yet.
+pub(crate) async fn get_due_notifications(conn: &sqlx::Pool<sqlx::Sqlite>, now: i64, limit: i64) -> Result<Vec<QueuedNotification>, DbError> {
+    let notifications = sqlx::query_as::<_, QueuedNotification>("SELECT notifications.id, users.chat_id, notifications.message, notifications.attempts 
+        FROM notifications JOIN users ON users.id = notifications.user_id 
+        where notifications.status = 'pending' and notifications.next_attempt_at <= ? and users.active 
+        and not exists (SELECT 1 FROM notifications earlier where earlier.user_id = notifications.user_id 
+            and earlier.status = 'pending' and earlier.id < notifications.id and earlier.next_attempt_at > ?)
+        order by notifications.id limit ?")
+    .bind(now)
+    .bind(now)
+    .bind(limit)
+    .fetch_all(conn)
+    .await?;
+    Ok(notifications)
+}
 
-    if query_res.is_err() { return Err(()); }
+pub(crate) async fn mark_notification_sent(conn: &sqlx::Pool<sqlx::Sqlite>, id: i64) -> Result<(), DbError> {
+    sqlx::query!("UPDATE notifications set status = 'sent', attempts = attempts + 1, sent_at = CURRENT_TIMESTAMP where id = ?", id)
+    .execute(conn).await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+// Without next_attempt_at the notification is given up on and goes to the dead letters.
+pub(crate) async fn mark_notification_failed(conn: &sqlx::Pool<sqlx::Sqlite>, id: i64, error: &str, next_attempt_at: Option<i64>) -> Result<(), DbError> {
+    let status = if next_attempt_at.is_some() { "pending" } else { "dead" };
+    let next_attempt_at = next_attempt_at.unwrap_or(0);
+    sqlx::query!("UPDATE notifications set status = ?, attempts = attempts + 1, last_error = ?, next_attempt_at = ? where id = ?", 
+        status, error, next_attempt_at, id)
+    .execute(conn).await?;
+    Ok(())
+}
+
+// Number of pending and dead notifications.
+pub(crate) async fn count_unsent_notifications(conn: &sqlx::Pool<sqlx::Sqlite>) -> Result<(i64, i64), DbError> {
+    let counts = sqlx::query_as::<_, (i64, i64)>("SELECT count(*) filter (where status = 'pending'), count(*) filter (where status = 'dead') FROM notifications")
+    .fetch_one(conn)
+    .await?;
+    Ok(counts)
+}
+
+// Passwords used to be stored in plaintext, this encrypts whatever is left of them.
+// It can't be a sql migration because the key only exists in the environment.
+pub(crate) async fn encrypt_plaintext_passwords(conn: &sqlx::Pool<sqlx::Sqlite>, cipher: &crypto::Cipher) -> Result<u64, DbError> {
+    let users = get_users(conn).await?;
+
+    let mut encrypted = 0;
+    for user in users {
+        if user.pwd.is_empty() || crypto::Cipher::is_encrypted(&user.pwd) { continue; }
+
+        let pwd = cipher.encrypt(&user.pwd);
+        sqlx::query!("UPDATE users set pwd = ? where id = ?", pwd, user.id)
+        .execute(conn).await?;
+        encrypted += 1;
+    }
+
+    Ok(encrypted)
+}
+
+pub(crate) async fn rotate_key(conn: &sqlx::Pool<sqlx::Sqlite>, old: &crypto::Cipher, new: &crypto::Cipher) -> Result<u64, DbError> {
+    let users = get_users(conn).await?;
+
+    let mut tx = conn.begin().await?;
+    let mut rotated = 0;
+    for user in users {
+        if user.pwd.is_empty() { continue; }
+
+        let pwd = if crypto::Cipher::is_encrypted(&user.pwd) {
+            old.decrypt(&user.pwd).ok_or(DbError::UndecryptablePassword { user_id: user.id })?
+        } else {
+            user.pwd
+        };
+
+        let pwd = new.encrypt(&pwd);
+        sqlx::query!("UPDATE users set pwd = ? where id = ?", pwd, user.id)
+        .execute(&mut tx).await?;
+        rotated += 1;
+    }
+
+    tx.commit().await?;
+    Ok(rotated)
+}
+
+// A fresh migrated database in memory, with one connection so that it isn't a new database each time.
+#[cfg(test)]
+pub(crate) async fn test_db() -> sqlx::Pool<sqlx::Sqlite> {
+    let conn = connect("sqlite::memory:", 1).await.unwrap();
+    migrate(&conn).await.unwrap();
+    conn
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::test_cipher;
+
+    fn test_subject() -> rating::Subject {
+        rating::Subject { name: "Экономика".to_string(), attendance: 10.0, control: 5.0, creative: 0.0, test: 0.0 }
+    }
+
+    #[tokio::test]
+    async fn users_are_only_created_explicitly() {
+        let conn = test_db().await;
+        assert!(matches!(find_user(&conn, 42).await, Err(DbError::NotFound)));
+        assert!(get_users(&conn).await.unwrap().is_empty());
+
+        let user = create_user(&conn, 42).await.unwrap();
+        assert_eq!(user.chat_id, 42);
+        assert_eq!(create_user(&conn, 42).await.unwrap().id, user.id);
+        assert_eq!(find_user(&conn, 42).await.unwrap().id, user.id);
+        assert_eq!(get_users(&conn).await.unwrap().len(), 1);
+        assert!(!activate_user(&conn, 42).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn blocked_users_are_deactivated() {
+        let conn = test_db().await;
+        create_user(&conn, 42).await.unwrap();
+
+        // Only a chat that was inactive comes back
+        deactivate_user(&conn, 42).await.unwrap();
+        assert!(activate_user(&conn, 42).await.unwrap());
+        assert!(!activate_user(&conn, 42).await.unwrap());
+        assert!(!activate_user(&conn, 43).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn notifications_are_sent_or_dead_lettered() {
+        let conn = test_db().await;
+        let user = create_user(&conn, 42).await.unwrap();
+        let mut db_conn = conn.acquire().await.unwrap();
+        queue_notification(&mut db_conn, &user, "first", 0).await.unwrap();
+        queue_notification(&mut db_conn, &user, "second", 0).await.unwrap();
+        drop(db_conn);
+
+        let due = get_due_notifications(&conn, 100, 10).await.unwrap();
+        assert_eq!(due.iter().map(|n| n.message.as_str()).collect::<Vec<&str>>(), vec!["first", "second"]);
+        assert_eq!(due[0].chat_id, 42);
+
+        mark_notification_sent(&conn, due[0].id).await.unwrap();
+        mark_notification_failed(&conn, due[1].id, "timeout", Some(200)).await.unwrap();
+        assert!(get_due_notifications(&conn, 100, 10).await.unwrap().is_empty());
+
+        // A later notification waits for the retry of the earlier one
+        queue_notification(&mut conn.acquire().await.unwrap(), &user, "third", 0).await.unwrap();
+        assert!(get_due_notifications(&conn, 100, 10).await.unwrap().is_empty());
+        let due = get_due_notifications(&conn, 200, 10).await.unwrap();
+        assert_eq!(due.iter().map(|n| n.message.as_str()).collect::<Vec<&str>>(), vec!["second", "third"]);
+        mark_notification_sent(&conn, due[1].id).await.unwrap();
+
+        let due = get_due_notifications(&conn, 200, 10).await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].attempts, 1);
+        assert_eq!(count_unsent_notifications(&conn).await.unwrap(), (1, 0));
+
+        mark_notification_failed(&conn, due[0].id, "timeout", None).await.unwrap();
+        assert!(get_due_notifications(&conn, i64::MAX, 10).await.unwrap().is_empty());
+        assert_eq!(count_unsent_notifications(&conn).await.unwrap(), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn notifications_wait_for_inactive_users() {
+        let conn = test_db().await;
+        let user = create_user(&conn, 42).await.unwrap();
+        queue_notification(&mut conn.acquire().await.unwrap(), &user, "hi", 0).await.unwrap();
+
+        deactivate_user(&conn, 42).await.unwrap();
+        assert!(get_due_notifications(&conn, 100, 10).await.unwrap().is_empty());
+
+        activate_user(&conn, 42).await.unwrap();
+        assert_eq!(get_due_notifications(&conn, 100, 10).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn preferences_default_and_save() {
+        let conn = test_db().await;
+        let user = create_user(&conn, 42).await.unwrap();
+        assert_eq!(get_preferences(&conn, &user).await.unwrap(), prefs::Preferences::default());
+        assert!(get_digest_users(&conn).await.unwrap().is_empty());
+
+        let mut preferences = prefs::Preferences::default();
+        preferences.toggle("attendance");
+        preferences.next_quiet_hours();
+        preferences.next_delivery(100);
+        preferences.next_digest_hour();
+        save_preferences(&conn, &user, &preferences).await.unwrap();
+        save_preferences(&conn, &user, &preferences).await.unwrap();
+
+        assert_eq!(get_preferences(&conn, &user).await.unwrap(), preferences);
+        assert_eq!(get_digest_users(&conn).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn logout_stops_polling_but_keeps_rating() {
+        let conn = test_db().await;
+        let mut user = create_user(&conn, 42).await.unwrap();
+        user.username = "student".to_string();
+        user.pwd = "enc1:secret".to_string();
+        user.semester = 7;
+        sync_user(&conn, &user).await.unwrap();
+        upsert_subject(&mut conn.acquire().await.unwrap(), &user, 7, &test_subject()).await.unwrap();
+
+        logout(&conn, &user).await.unwrap();
+
+        let user = find_user(&conn, 42).await.unwrap();
+        assert!(user.username.is_empty() && user.pwd.is_empty());
+        assert_eq!(get_rating(&conn, &user, 7).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn delete_user_removes_everything() {
+        let conn = test_db().await;
+        let user = create_user(&conn, 42).await.unwrap();
+        let other = create_user(&conn, 43).await.unwrap();
+        for user in [&user, &other] {
+            let mut db_conn = conn.acquire().await.unwrap();
+            upsert_subject(&mut db_conn, user, 7, &test_subject()).await.unwrap();
+            insert_history(&mut db_conn, user, 7, "Экономика", "attendance", None, 10.0).await.unwrap();
+        }
+
+        delete_user(&conn, &user).await.unwrap();
+
+        let users = get_users(&conn).await.unwrap();
+        assert_eq!(users.iter().map(|user| user.id).collect::<Vec<i64>>(), vec![other.id]);
+        assert!(get_history(&conn, &user, 7, "Экономика").await.unwrap().is_empty());
+        assert_eq!(get_history(&conn, &other, 7, "Экономика").await.unwrap().len(), 1);
+        let ratings: i64 = sqlx::query_scalar("SELECT count(*) FROM rating").fetch_one(&conn).await.unwrap();
+        assert_eq!(ratings, 1);
+
+        assert!(matches!(get_rating(&conn, &user, 7).await, Err(DbError::NotFound)));
+        assert!(matches!(sync_user(&conn, &user).await, Err(DbError::NotFound)));
+    }
+
+    async fn user_with_pwd(conn: &sqlx::Pool<sqlx::Sqlite>, chat_id: i64, pwd: &str) -> User {
+        let mut user = create_user(conn, chat_id).await.unwrap();
+        user.username = "student".to_string();
+        user.pwd = pwd.to_string();
+        sync_user(conn, &user).await.unwrap();
+        user
+    }
+
+    #[tokio::test]
+    async fn plaintext_passwords_are_encrypted_once() {
+        let conn = test_db().await;
+        let cipher = test_cipher();
+        let plain = user_with_pwd(&conn, 42, "secret").await;
+        let encrypted = user_with_pwd(&conn, 43, &cipher.encrypt("other")).await;
+        create_user(&conn, 44).await.unwrap();
+
+        assert_eq!(encrypt_plaintext_passwords(&conn, &cipher).await.unwrap(), 1);
+        let stored = find_user(&conn, plain.chat_id).await.unwrap().pwd;
+        assert!(crypto::Cipher::is_encrypted(&stored));
+        assert_eq!(cipher.decrypt(&stored).as_deref(), Some("secret"));
+        assert_eq!(find_user(&conn, encrypted.chat_id).await.unwrap().pwd, encrypted.pwd);
+        assert!(find_user(&conn, 44).await.unwrap().pwd.is_empty());
+
+        assert_eq!(encrypt_plaintext_passwords(&conn, &cipher).await.unwrap(), 0);
+        assert_eq!(find_user(&conn, plain.chat_id).await.unwrap().pwd, stored);
+    }
+
+    #[tokio::test]
+    async fn rotate_key_reencrypts_everything_or_nothing() {
+        let conn = test_db().await;
+        let old = test_cipher();
+        let new = test_cipher();
+        let first = user_with_pwd(&conn, 42, &old.encrypt("first")).await;
+        let second = user_with_pwd(&conn, 43, &old.encrypt("second")).await;
+        let stranger = user_with_pwd(&conn, 44, &new.encrypt("stranger")).await;
+
+        // The password under another key stops the whole rotation
+        let res = rotate_key(&conn, &old, &new).await;
+        assert!(matches!(res, Err(DbError::UndecryptablePassword { user_id }) if user_id == stranger.id));
+        for user in [&first, &second, &stranger] {
+            assert_eq!(find_user(&conn, user.chat_id).await.unwrap().pwd, user.pwd);
+        }
+
+        delete_user(&conn, &stranger).await.unwrap();
+        assert_eq!(rotate_key(&conn, &old, &new).await.unwrap(), 2);
+        let stored = find_user(&conn, second.chat_id).await.unwrap().pwd;
+        assert_eq!(new.decrypt(&stored).as_deref(), Some("second"));
+        assert_eq!(old.decrypt(&stored), None);
+    }
+
+    #[tokio::test]
+    async fn history_is_kept_per_semester() {
+        let conn = test_db().await;
+        let user = create_user(&conn, 42).await.unwrap();
+        let mut db_conn = conn.acquire().await.unwrap();
+        insert_history(&mut db_conn, &user, 6, "Иностранный язык", "attendance", None, 20.0).await.unwrap();
+        insert_history(&mut db_conn, &user, 7, "Иностранный язык", "attendance", None, 0.0).await.unwrap();
+        insert_history(&mut db_conn, &user, 7, "Иностранный язык", "attendance", Some(0.0), 4.0).await.unwrap();
+        insert_history(&mut db_conn, &user, 7, "Экономика", "attendance", None, 2.0).await.unwrap();
+        drop(db_conn);
+
+        let mut names = get_subject_names(&conn, &user, 7).await.unwrap();
+        names.sort();
+        assert_eq!(names, vec!["Иностранный язык", "Экономика"]);
+        assert_eq!(get_subject_names(&conn, &user, 6).await.unwrap(), vec!["Иностранный язык"]);
+
+        let history = get_history(&conn, &user, 7, "Иностранный язык").await.unwrap();
+        assert_eq!(history.iter().map(|entry| entry.new_value).collect::<Vec<f32>>(), vec![0.0, 4.0]);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::db;
+use crate::limit;
+use crate::tg;
+use std::sync::Arc;
+use crate::prefs::unix_now;
+use std::time::Duration;
+
+// Notifications that fail this many times go to the dead letters
+const MAX_SEND_ATTEMPTS: i64 = 5;
+const BATCH_SIZE: i64 = 50;
+// Retries come due on their own, the queue is looked at this often even without a wake up.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+// Sends queued notifications until the queue has nothing due. Delivery is at least once:
+// a notification is marked sent only after Telegram has accepted it.
+// Chats are sent to in parallel, the notifications of one chat one after another in the order they were queued.
+async fn drain(conn: &sqlx::Pool<sqlx::Sqlite>, bot: &tg::TgBot) -> Result<(), db::DbError> {
+    loop {
+        let notifications = db::get_due_notifications(conn, unix_now(), BATCH_SIZE).await?;
+        if notifications.is_empty() {
+            return Ok(());
+        }
+
+        // The batch comes ordered by id, so each chat's notifications stay in order
+        let mut chats: Vec<Vec<db::QueuedNotification>> = vec![];
+        for notification in notifications {
+            match chats.iter_mut().find(|chat| chat[0].chat_id == notification.chat_id) {
+                Some(chat) => chat.push(notification),
+                None => chats.push(vec![notification])
+            }
+        }
+
+        let mut set = tokio::task::JoinSet::new();
+        for chat in chats {
+            let bot = bot.clone();
+            set.spawn(async move {
+                // After a failure the rest of the chat's notifications wait for it, they are left pending
+                let mut sent = vec![];
+                for notification in chat {
+                    let res = tg::send_markdown(&bot, notification.chat_id, &notification.message).await;
+                    let failed = res.is_err();
+                    sent.push((notification, res));
+                    if failed {
+                        break;
+                    }
+                }
+                sent
+            });
+        }
+
+        while let Some(res) = set.join_next().await {
+            let sent = match res {
+                Ok(sent) => sent,
+                Err(err) => {
+                    log::error!("Notification task failed: {}", err);
+                    continue;
+                }
+            };
+
+            for (notification, res) in sent {
+                record_result(conn, &notification, res).await?;
+            }
+        }
+    }
+}
+
+async fn record_result(conn: &sqlx::Pool<sqlx::Sqlite>, notification: &db::QueuedNotification, res: Result<(), tg::SendError>) -> Result<(), db::DbError> {
+    match res {
+        Ok(()) => db::mark_notification_sent(conn, notification.id).await?,
+        Err(err @ tg::SendError::Unreachable(_)) => {
+            log::info!("Deactivating chat {}: {}", notification.chat_id, err);
+            db::deactivate_user(conn, notification.chat_id).await?;
+            db::mark_notification_failed(conn, notification.id, &err.to_string(), None).await?;
+        }
+        Err(err) if notification.attempts + 1 < MAX_SEND_ATTEMPTS => {
+            let delay = limit::backoff(notification.attempts as u32);
+            log::warn!("Couldn't send notification {} to chat {} ({}), retrying in {:?}", notification.id, notification.chat_id, err, delay);
+            db::mark_notification_failed(conn, notification.id, &err.to_string(), Some(unix_now() + delay.as_secs().max(1) as i64)).await?;
+        }
+        Err(err) => {
+            log::error!("Giving up on notification {} to chat {}: {}", notification.id, notification.chat_id, err);
+            db::mark_notification_failed(conn, notification.id, &err.to_string(), None).await?;
+        }
+    }
+    Ok(())
+}
+
+// Runs for the lifetime of the bot, `queued` wakes it up when new notifications are written.
+pub(crate) async fn run_dispatcher(conn: sqlx::Pool<sqlx::Sqlite>, bot: tg::TgBot, queued: Arc<tokio::sync::Notify>) {
+    loop {
+        if let Err(err) = drain(&conn, &bot).await {
+            log::error!("Couldn't dispatch notifications: {}", err);
+        }
+        let _ = tokio::time::timeout(POLL_INTERVAL, queued.notified()).await;
+    }
+}
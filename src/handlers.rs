@@ -1,52 +1,307 @@
+use std::ops::RangeInclusive;
 use teloxide::{
+    dispatching::dialogue::InMemStorage,
     prelude::*,
-    types::{InlineQueryResult, InlineQueryResultArticle, InputMessageContent, InputMessageContentText},
+    types::{
+        InlineKeyboardButton, InlineKeyboardMarkup, InlineQueryResult, InlineQueryResultArticle, InputMessageContent,
+        InputMessageContentText,
+    },
     utils::command::BotCommands,
 };
+use crate::crypto;
 use crate::db;
+use crate::prefs;
+use crate::rating;
+use crate::rating::{RatingError, RatingSource};
 use crate::{Command, Config};
 use crate::tg;
+use crate::tg::TgBot;
+
+// Writing to the bot or pressing its buttons means it isn't blocked anymore,
+// runs before every message and callback query handler.
+pub(crate) async fn reactivate_chat(cfg: &Config, chat_id: ChatId) {
+    match db::activate_user(&cfg.conn, chat_id.0).await {
+        Ok(true) => log::info!("Chat {} is back", chat_id),
+        Ok(false) => (),
+        Err(err) => log::error!("Couldn't activate chat {}: {}", chat_id, err)
+    }
+}
+
+// Err holds the reply explaining what's wrong.
+fn parse_semester(arg: &str, semesters: &RangeInclusive<u8>) -> Result<u8, String> {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        return Err("Надо указать номер семестра".to_string());
+    }
+
+    match arg.parse::<i64>() {
+        Ok(semester) => match u8::try_from(semester) {
+            Ok(semester) if semesters.contains(&semester) => Ok(semester),
+            _ => Err(format!("Семестр, епта, от {} до {}, если кто не знал", semesters.start(), semesters.end()))
+        },
+        Err(_) => Err(format!("\"{}\" не похоже на номер семестра, нужно число", arg))
+    }
+}
+
+// Steps of /logininfo, the login and the password come in separate messages
+// so that the password one can be deleted right after reading it.
+#[derive(Clone, Default)]
+pub(crate) enum LoginState {
+    #[default]
+    Idle,
+    ReceiveLogin,
+    ReceivePassword { username: String }
+}
+
+pub(crate) type LoginDialogue = Dialogue<LoginState, InMemStorage<LoginState>>;
+
+async fn set_login_state(dialogue: &LoginDialogue, state: LoginState) {
+    if let Err(err) = dialogue.update(state).await {
+        log::error!("Couldn't update login dialogue of chat {}: {}", dialogue.chat_id(), err);
+    }
+}
+
+// Only the login is tried, whatever happens to the rating page after it says nothing about the credentials.
+// An outage or an unexpected page on login is an error, not a wrong password.
+async fn verify_credentials(source: &dyn RatingSource, cipher: &crypto::Cipher, user: db::User) -> Result<(), RatingError> {
+    let pwd = cipher.decrypt(&user.pwd).ok_or(RatingError::BadCredentials)?;
+    source.authenticate(&user, &pwd).await
+}
+
+pub(crate) async fn receive_login(bot: TgBot, dialogue: LoginDialogue, msg: Message) -> Result<(), teloxide::RequestError> {
+    match msg.text().map(str::trim) {
+        Some(username) if !username.is_empty() => {
+            set_login_state(&dialogue, LoginState::ReceivePassword { username: username.to_string() }).await;
+            bot.send_message(msg.chat.id, "Теперь пароль, сообщение с ним я сразу удалю").await?;
+        }
+        _ => { bot.send_message(msg.chat.id, "Пришли логин текстом").await?; }
+    }
+    Ok(())
+}
+
+pub(crate) async fn receive_password(
+    bot: TgBot,
+    cfg: Config,
+    dialogue: LoginDialogue,
+    username: String,
+    msg: Message,
+) -> Result<(), teloxide::RequestError> {
+    let pwd = match msg.text() {
+        Some(pwd) if !pwd.is_empty() => pwd.to_string(),
+        _ => {
+            bot.send_message(msg.chat.id, "Пришли пароль текстом").await?;
+            return Ok(());
+        }
+    };
+
+    if let Err(err) = bot.delete_message(msg.chat.id, msg.id).await {
+        log::warn!("Couldn't delete password message in chat {}: {}", msg.chat.id, err);
+        bot.send_message(msg.chat.id, "Не получилось удалить сообщение с паролем, удали его сам").await?;
+    }
+    set_login_state(&dialogue, LoginState::Idle).await;
+
+    // /logininfo has registered the user already
+    let mut user = match db::find_user(&cfg.conn, msg.chat.id.0).await {
+        Ok(user) => user,
+        Err(db::DbError::NotFound) => {
+            bot.send_message(msg.chat.id, START_FIRST).await?;
+            return Ok(());
+        }
+        Err(err) => {
+            log::error!("Couldn't find user with chat id {}: {}", msg.chat.id, err);
+            bot.send_message(msg.chat.id, DB_BROKEN).await?;
+            return Ok(());
+        }
+    };
+    user.username = username;
+    user.pwd = cfg.cipher.encrypt(&pwd);
+    user.auth_failures = 0;
+    user.auth_failure_notified = false;
+
+    bot.send_message(msg.chat.id, "Проверяю на портале...").await?;
+    match verify_credentials(cfg.source.as_ref(), &cfg.cipher, user.clone()).await {
+        Ok(()) => (),
+        Err(RatingError::BadCredentials) => {
+            bot.send_message(msg.chat.id, "Неправильный логин или пароль, попробуй еще раз: /logininfo").await?;
+            return Ok(());
+        }
+        Err(err) => {
+            log::warn!("Couldn't verify credentials of user {}: {}", user.id, err);
+            bot.send_message(msg.chat.id, "Не получилось проверить пароль, портал не отвечает, попробуй позже: /logininfo").await?;
+            return Ok(());
+        }
+    }
+
+    let text = match db::sync_user(&cfg.conn, &user).await {
+        Ok(()) => "👌",
+        Err(db::DbError::NotFound) => START_FIRST,
+        Err(err) => {
+            log::error!("Couldn't save credentials of user {}: {}", user.id, err);
+            DB_BROKEN
+        }
+    };
+    bot.send_message(msg.chat.id, text).await?;
+    Ok(())
+}
+
+const START_FIRST: &str = "Сначала нажми /start";
+const DB_BROKEN: &str = "⚠️ Что-то сломалось в базе, попробуй позже";
+
+const DELETE_CONFIRM: &str = "deleteme:confirm";
+const DELETE_CANCEL: &str = "deleteme:cancel";
+
+const SETTINGS_PREFIX: &str = "settings:";
+const SETTINGS_TEXT: &str = "Настройки уведомлений, время московское. Нажми, чтобы поменять:";
+// Indexed by prefs::Preferences::digest_weekday
+const WEEKDAYS: [&str; 7] = ["понедельникам", "вторникам", "средам", "четвергам", "пятницам", "субботам", "воскресеньям"];
+
+fn settings_keyboard(preferences: &prefs::Preferences) -> InlineKeyboardMarkup {
+    let component = |component: &str| {
+        let mark = if preferences.notifies(component) { "✅" } else { "❌" };
+        InlineKeyboardButton::callback(format!("{} {}", mark, rating::component_name(component)), format!("{}{}", SETTINGS_PREFIX, component))
+    };
+    let quiet = match preferences.quiet_hours() {
+        Some((start, end)) => format!("🌙 Тихие часы: {}:00–{}:00", start, end),
+        None => "🌙 Тихие часы: нет".to_string()
+    };
+    let delivery = match preferences.delivery {
+        prefs::Delivery::Instant => "📬 Присылать сразу",
+        prefs::Delivery::Daily => "📬 Раз в день",
+        prefs::Delivery::Weekly => "📬 Раз в неделю"
+    };
+
+    let mut keyboard = vec![
+        vec![component("attendance"), component("creative")],
+        vec![component("control"), component("test")],
+        vec![InlineKeyboardButton::callback(quiet, format!("{}quiet", SETTINGS_PREFIX))],
+        vec![InlineKeyboardButton::callback(delivery, format!("{}delivery", SETTINGS_PREFIX))],
+    ];
+    // The digest time only matters when there is a digest
+    let mut schedule = vec![];
+    if preferences.delivery == prefs::Delivery::Weekly {
+        let weekday = format!("📅 По {}", WEEKDAYS[preferences.digest_weekday as usize % WEEKDAYS.len()]);
+        schedule.push(InlineKeyboardButton::callback(weekday, format!("{}weekday", SETTINGS_PREFIX)));
+    }
+    if preferences.delivery != prefs::Delivery::Instant {
+        schedule.push(InlineKeyboardButton::callback(format!("🕗 В {}:00", preferences.digest_hour), format!("{}hour", SETTINGS_PREFIX)));
+        keyboard.push(schedule);
+    }
+    InlineKeyboardMarkup::new(keyboard)
+}
+
+// A press on one of the /settings buttons, the keyboard is redrawn with the new values.
+async fn change_setting(bot: &TgBot, cfg: &Config, message: &Message, setting: &str) -> Result<(), teloxide::RequestError> {
+    let changed = async {
+        let user = db::find_user(&cfg.conn, message.chat.id.0).await?;
+        let mut preferences = db::get_preferences(&cfg.conn, &user).await?;
+        match setting {
+            "quiet" => preferences.next_quiet_hours(),
+            "delivery" => preferences.next_delivery(prefs::unix_now()),
+            "hour" => preferences.next_digest_hour(),
+            "weekday" => preferences.next_digest_weekday(),
+            component => preferences.toggle(component)
+        }
+        db::save_preferences(&cfg.conn, &user, &preferences).await?;
+        Ok::<_, db::DbError>(preferences)
+    }.await;
+
+    match changed {
+        Ok(preferences) => { bot.edit_message_reply_markup(message.chat.id, message.id).reply_markup(settings_keyboard(&preferences)).await?; }
+        Err(db::DbError::NotFound) => { bot.edit_message_text(message.chat.id, message.id, START_FIRST).await?; }
+        Err(err) => {
+            log::error!("Couldn't change setting {} in chat {}: {}", setting, message.chat.id, err);
+            bot.edit_message_text(message.chat.id, message.id, DB_BROKEN).await?;
+        }
+    }
+    Ok(())
+}
+
+// Buttons under the /deleteme question and the /settings menu.
+pub(crate) async fn callback_query_handler(bot: TgBot, cfg: Config, q: CallbackQuery) -> Result<(), teloxide::RequestError> {
+    bot.answer_callback_query(&q.id).await?;
+    let Some(message) = q.message else { return Ok(()) };
+
+    if let Some(setting) = q.data.as_deref().and_then(|data| data.strip_prefix(SETTINGS_PREFIX)) {
+        return change_setting(&bot, &cfg, &message, setting).await;
+    }
+
+    let text = match q.data.as_deref() {
+        Some(DELETE_CONFIRM) => {
+            let deleted = match db::find_user(&cfg.conn, message.chat.id.0).await {
+                Ok(user) => db::delete_user(&cfg.conn, &user).await.map(|()| user),
+                Err(err) => Err(err)
+            };
+            match deleted {
+                Ok(user) => {
+                    cfg.source.forget(&user);
+                    log::info!("User {} deleted their data", user.id);
+                    "Удалил все, что о тебе знал. Если захочешь вернуться: /start"
+                }
+                Err(db::DbError::NotFound) => "Про тебя и так ничего не знаю",
+                Err(err) => {
+                    log::error!("Couldn't delete user with chat id {}: {}", message.chat.id, err);
+                    DB_BROKEN
+                }
+            }
+        }
+        Some(DELETE_CANCEL) => "Ну и правильно",
+        _ => return Ok(())
+    };
+
+    bot.edit_message_text(message.chat.id, message.id, text).await?;
+    Ok(())
+}
+
+// Commands that are known but whose arguments didn't parse end up here.
+pub(crate) async fn bad_command_handler(bot: TgBot, msg: Message) -> Result<(), teloxide::RequestError> {
+    bot.send_message(msg.chat.id, "Не понял команду, проверь аргументы в /help").await?;
+    Ok(())
+}
+
+async fn answer_inline_error(bot: &TgBot, q: &InlineQuery, title: &str) {
+    let answer = InlineQueryResultArticle::new(
+        "1".to_string(),
+        title.to_string(),
+        InputMessageContent::Text(InputMessageContentText::new("⚠️"))
+    );
+    let results = vec![InlineQueryResult::Article(answer)];
+    let response = bot.answer_inline_query(&q.id, results).send().await;
+    if let Err(err) = response {
+        log::error!("Error in handler: {:?}", err);
+    }
+}
 
 pub(crate) async fn inline_query_handler(
-    bot: Bot,
+    bot: TgBot,
     cfg: crate::Config,
     q: InlineQuery,
 ) -> Result<(), teloxide::RequestError> {
-    let user = db::get_user(&cfg.conn, q.from.id.0 as i64).await;
-
-    if user.is_none() {
-        let answer = InlineQueryResultArticle::new(
-            "1".to_string(),
-            "There has been an error".to_string(),
-            InputMessageContent::Text(InputMessageContentText::new("⚠️"))
-        );
-        let results = vec![InlineQueryResult::Article(answer)];
-        let response = bot.answer_inline_query(&q.id, results).send().await;
-        if let Err(err) = response {
-            log::error!("Error in handler: {:?}", err);
-        }
-        return respond(());
-    } 
-
-    let user = user.unwrap();
-    let rating = db::get_rating(&cfg.conn, &user).await;
-
-    if rating.is_none() {
-        let answer = InlineQueryResultArticle::new(
-            "1".to_string(),
-            "У тебя нету рейтинга".to_string(),
-            InputMessageContent::Text(InputMessageContentText::new("⚠️"))
-        );
-        let results = vec![InlineQueryResult::Article(answer)];
-        let response = bot.answer_inline_query(&q.id, results).send().await;
-        if let Err(err) = response {
-            log::error!("Error in handler: {:?}", err);
+    // Strangers aren't registered here, only /start and /logininfo do that
+    let user = match db::find_user(&cfg.conn, q.from.id.0 as i64).await {
+        Ok(user) => user,
+        Err(db::DbError::NotFound) => {
+            answer_inline_error(&bot, &q, "Сначала напиши боту /start").await;
+            return respond(());
+        }
+        Err(err) => {
+            log::error!("Couldn't find user with id {}: {}", q.from.id, err);
+            answer_inline_error(&bot, &q, "There has been an error").await;
+            return respond(());
         }
+    };
 
-        return respond(());
-    }
-    
-    let rating = rating.unwrap();
+    let rating = match db::get_rating(&cfg.conn, &user, user.semester).await {
+        Ok(rating) => rating,
+        Err(db::DbError::NotFound) => {
+            answer_inline_error(&bot, &q, "У тебя нету рейтинга").await;
+            return respond(());
+        }
+        Err(err) => {
+            log::error!("Couldn't get rating of user {}: {}", user.id, err);
+            answer_inline_error(&bot, &q, "There has been an error").await;
+            return respond(());
+        }
+    };
 
     let mut results = vec![];
     for (subject_num, subject) in rating.into_iter().enumerate() {
@@ -69,85 +324,219 @@ pub(crate) async fn inline_query_handler(
 }
 
 pub(crate) async fn commands_handler(
-    bot: Bot,
+    bot: TgBot,
     cfg: Config,
+    dialogue: LoginDialogue,
     msg: Message,
     cmd: Command,
 ) -> Result<(), teloxide::RequestError> {
-    let user = db::get_user(&cfg.conn, msg.chat.id.0).await;
-    if user.is_none() {
-        bot.send_message(msg.chat.id, "⚠️").await?;
+    // Any other command abandons a half-finished login
+    set_login_state(&dialogue, LoginState::Idle).await;
+
+    if let Command::Help = cmd {
+        bot.send_message(msg.chat.id, Command::descriptions().to_string()).await?;
         return Ok(());
-    } 
-    let mut user = user.unwrap();
+    }
+
+    // Only these register the chat, everything else needs a registered user
+    let user = match cmd {
+        Command::Start | Command::LoginInfo { .. } => db::create_user(&cfg.conn, msg.chat.id.0).await,
+        _ => db::find_user(&cfg.conn, msg.chat.id.0).await
+    };
+    let mut user = match user {
+        Ok(user) => user,
+        Err(db::DbError::NotFound) => {
+            bot.send_message(msg.chat.id, START_FIRST).await?;
+            return Ok(());
+        }
+        Err(err) => {
+            log::error!("Couldn't get user with chat id {}: {}", msg.chat.id, err);
+            bot.send_message(msg.chat.id, DB_BROKEN).await?;
+            return Ok(());
+        }
+    };
 
     match cmd {
         Command::Start => { bot.send_message(msg.chat.id, "😏").await?; }
-        Command::Help => { bot.send_message(msg.chat.id, Command::descriptions().to_string()).await?; }
-        Command::LoginInfo { username, pwd } => {
-            user.username = username;
-            user.pwd = pwd;
-
-            let sync_res = db::sync_user(&cfg.conn, &user).await;
-            let text = match sync_res {
-                Ok(()) => "👌",
-                Err(()) => "⚠️",
+        Command::Help => (),
+        Command::LoginInfo { credentials } => {
+            // The old `/logininfo login password` form shouldn't stay in the history either
+            if !credentials.trim().is_empty() {
+                if let Err(err) = bot.delete_message(msg.chat.id, msg.id).await {
+                    log::warn!("Couldn't delete credentials message in chat {}: {}", msg.chat.id, err);
+                }
+            }
+
+            set_login_state(&dialogue, LoginState::ReceiveLogin).await;
+            bot.send_message(msg.chat.id, "Пришли логин от портала").await?;
+        }
+        Command::SetSemester { semester } => {
+            let semester = match parse_semester(&semester, &cfg.semesters) {
+                Ok(semester) => semester,
+                Err(text) => {
+                    bot.send_message(msg.chat.id, format!("{} (/setsemester 7)", text)).await?;
+                    return Ok(());
+                }
+            };
+
+            user.semester = semester;
+            let text = match db::sync_user(&cfg.conn, &user).await {
+                Ok(()) => "👌, Если рейтинга за этот семестр у меня еще нет, придется подождать, я пришлю уведомление (это займет не больше 20 минут))",
+                Err(db::DbError::NotFound) => START_FIRST,
+                Err(err) => {
+                    log::error!("Couldn't save semester of user {}: {}", user.id, err);
+                    DB_BROKEN
+                }
             };
             bot.send_message(msg.chat.id, text).await?;
         }
-        Command::SetSemester { semester } => {
-            if !semester > 0 && !semester < 9 {
-                bot.send_message(msg.chat.id, "Семестр, епта, от 1 до 8, если кто не знал").await?;
+        Command::GetRating { semester } => {
+            // The stored rating is shown after /logout too, the credentials only matter for what hasn't loaded yet
+            if user.semester == 0 {
+                let text = if user.username.is_empty() || user.pwd.is_empty() {
+                    "Надо ввести логин и пароль"
+                } else {
+                    "Рейтинг еще не загрузился, я пришлю уведомление (это займет не больше 20 минут)"
+                };
+                bot.send_message(msg.chat.id, text).await?;
                 return Ok(());
             }
 
-            user.semester = semester as u8;
-            let sync_res = db::sync_user(&cfg.conn, &user).await;
+            let semester = match semester.trim() {
+                "" => user.semester,
+                semester => match parse_semester(semester, &cfg.semesters) {
+                    Ok(semester) => semester,
+                    Err(text) => {
+                        bot.send_message(msg.chat.id, format!("{} (/getrating 5)", text)).await?;
+                        return Ok(());
+                    }
+                }
+            };
 
-            if sync_res.is_ok() {
-                let del_res = db::delete_rating(&cfg.conn, &user).await;
-                if del_res.is_ok() {
-                    bot.send_message(msg.chat.id, "👌, Теперь придется подождать, пока твой рейтинг обновится, я пришлю уведомление (это займет не больше 20 минут))").await?;
+            let rating = match db::get_rating(&cfg.conn, &user, semester).await {
+                Ok(rating) => rating,
+                Err(db::DbError::NotFound) => {
+                    bot.send_message(msg.chat.id, format!("Рейтинга за {} семестр у меня пока нет", semester)).await?;
                     return Ok(());
                 }
-            }
-            bot.send_message(msg.chat.id, "⚠️").await?;
-        }
-        Command::GetRating => {
-            if user.username.is_empty() || user.pwd.is_empty() || user.semester == 0 {
-                bot.send_message(msg.chat.id, "Надо ввести логин, пароль и семестр").await?;
-                return Ok(());
-            } 
-
-            let rating = db::get_rating(&cfg.conn, &user).await;
-            if rating.is_none() {
-                bot.send_message(msg.chat.id, "У тебя пустой рейтинг").await?;
-                return Ok(());
-            } 
+                Err(err) => {
+                    log::error!("Couldn't get rating of user {}: {}", user.id, err);
+                    bot.send_message(msg.chat.id, DB_BROKEN).await?;
+                    return Ok(());
+                }
+            };
 
             let text = rating
-            .unwrap()
             .iter()
             .map(|subject| subject.to_string())
             .collect::<Vec<String>>()
             .join("\n\n");
 
-            bot.send_message(msg.chat.id, text).await?;    
+            tg::send_long(&bot, msg.chat.id, &text).await?;
         }
-        Command::Stats => {
-            if msg.chat.id != cfg.bot_maintainer.into() {
-                bot.send_message(msg.chat.id, "😑").await?;
+        Command::History { subject } => {
+            let query = subject.trim().to_lowercase();
+            if query.is_empty() {
+                bot.send_message(msg.chat.id, "Надо указать предмет (/history экономика)").await?;
                 return Ok(());
             }
 
-            let users = db::get_users(&cfg.conn).await;
-            if users.is_none() {
-                bot.send_message(msg.chat.id, "⚠️").await?;
+            let names = match db::get_subject_names(&cfg.conn, &user, user.semester).await {
+                Ok(names) => names,
+                Err(err) => {
+                    log::error!("Couldn't get subject names of user {}: {}", user.id, err);
+                    bot.send_message(msg.chat.id, DB_BROKEN).await?;
+                    return Ok(());
+                }
+            };
+
+            let matches: Vec<String> = names.into_iter().filter(|name| name.to_lowercase().contains(&query)).collect();
+            let subject_name = match matches.as_slice() {
+                [] => {
+                    bot.send_message(msg.chat.id, "Не знаю такого предмета").await?;
+                    return Ok(());
+                }
+                [name] => name,
+                names => match names.iter().find(|name| name.to_lowercase() == query) {
+                    Some(name) => name,
+                    None => {
+                        bot.send_message(msg.chat.id, format!("Подходит несколько предметов:\n{}", names.join("\n"))).await?;
+                        return Ok(());
+                    }
+                }
+            };
+
+            let history = match db::get_history(&cfg.conn, &user, user.semester, subject_name).await {
+                Ok(history) => history,
+                Err(err) => {
+                    log::error!("Couldn't get history of user {}: {}", user.id, err);
+                    bot.send_message(msg.chat.id, DB_BROKEN).await?;
+                    return Ok(());
+                }
+            };
+
+            let lines = history
+            .iter()
+            .map(|entry| match entry.old_value {
+                Some(old_value) => format!("{}: {} {} → {}", entry.changed_at, rating::component_name(&entry.component), old_value, entry.new_value),
+                None => format!("{}: {} {}", entry.changed_at, rating::component_name(&entry.component), entry.new_value)
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+
+            tg::send_long(&bot, msg.chat.id, &format!("{}:\n{}", subject_name, lines)).await?;
+        }
+        Command::Settings => {
+            let preferences = match db::get_preferences(&cfg.conn, &user).await {
+                Ok(preferences) => preferences,
+                Err(err) => {
+                    log::error!("Couldn't get preferences of user {}: {}", user.id, err);
+                    bot.send_message(msg.chat.id, DB_BROKEN).await?;
+                    return Ok(());
+                }
+            };
+            bot.send_message(msg.chat.id, SETTINGS_TEXT)
+                .reply_markup(settings_keyboard(&preferences))
+                .await?;
+        }
+        Command::Logout => {
+            // The portal session is dropped even if the database is broken, it's logged in with the old credentials
+            cfg.source.forget(&user);
+            let text = match db::logout(&cfg.conn, &user).await {
+                Ok(()) => "👌, Логин и пароль забыл, рейтинг больше проверять не буду. Вернуть: /logininfo",
+                Err(err) => {
+                    log::error!("Couldn't log out user {}: {}", user.id, err);
+                    DB_BROKEN
+                }
+            };
+            bot.send_message(msg.chat.id, text).await?;
+        }
+        Command::DeleteMe => {
+            let keyboard = InlineKeyboardMarkup::new([[
+                InlineKeyboardButton::callback("Да, удалить", DELETE_CONFIRM),
+                InlineKeyboardButton::callback("Нет", DELETE_CANCEL),
+            ]]);
+            bot.send_message(msg.chat.id, "Удалить логин, пароль, рейтинг и историю изменений? Это не отменить")
+                .reply_markup(keyboard)
+                .await?;
+        }
+        Command::Stats => {
+            if !cfg.bot_maintainers.iter().any(|maintainer| ChatId::from(*maintainer) == msg.chat.id) {
+                bot.send_message(msg.chat.id, "😑").await?;
                 return Ok(());
             }
+
+            let users = match db::get_users(&cfg.conn).await {
+                Ok(users) => users,
+                Err(err) => {
+                    log::error!("Couldn't get users: {}", err);
+                    bot.send_message(msg.chat.id, DB_BROKEN).await?;
+                    return Ok(());
+                }
+            };
             
             let mut handles= Vec::new();
-            for user in users.unwrap() {
+            for user in users {
                 handles.push(tokio::spawn(tg::get_user_string(bot.clone(), user)));
             }
             
@@ -160,9 +549,67 @@ pub(crate) async fn commands_handler(
                 }
             }
 
-            bot.send_message(msg.chat.id, results.join("\n")).await?;
+            match db::count_unsent_notifications(&cfg.conn).await {
+                Ok((pending, dead)) => results.push(format!("\nNotifications: {} pending, {} dead", pending, dead)),
+                Err(err) => results.push(format!("\nCouldn't count notifications: {}", err))
+            }
+
+            match db::get_last_cycle_summary(&cfg.conn).await {
+                Ok((finished_at, summary)) => results.push(format!("\nLast update ({}): {}", finished_at, summary)),
+                Err(db::DbError::NotFound) => results.push("\nNo updates yet".to_string()),
+                Err(err) => results.push(format!("\nCouldn't get the last update: {}", err))
+            }
+
+            tg::send_long(&bot, msg.chat.id, &results.join("\n")).await?;
         }
     };
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockSource;
+    use crate::testing::{test_cipher, test_user};
+
+    const RATING_PAGE: &str = include_str!("../tests/fixtures/rating.html");
+
+    #[tokio::test]
+    async fn verify_credentials_checks_password() {
+        let cipher = test_cipher();
+        let source = MockSource::default().with_password("secret").with_semester(7, RATING_PAGE);
+
+        assert!(verify_credentials(&source, &cipher, test_user(&cipher, "secret", 0)).await.is_ok());
+        assert!(matches!(verify_credentials(&source, &cipher, test_user(&cipher, "wrong", 0)).await, Err(RatingError::BadCredentials)));
+    }
+
+    #[tokio::test]
+    async fn verify_credentials_accepts_missing_semester() {
+        let cipher = test_cipher();
+        let source = MockSource::default().with_password("secret").with_semester(7, RATING_PAGE);
+
+        assert!(verify_credentials(&source, &cipher, test_user(&cipher, "secret", 8)).await.is_ok());
+    }
+
+    #[test]
+    fn parse_semester_accepts_range() {
+        assert_eq!(parse_semester("1", &(1..=8)), Ok(1));
+        assert_eq!(parse_semester(" 8 ", &(1..=8)), Ok(8));
+        assert_eq!(parse_semester("12", &(1..=12)), Ok(12));
+    }
+
+    #[test]
+    fn parse_semester_rejects_out_of_range() {
+        for arg in ["0", "9", "-1", "200", "264", "99999999999"] {
+            assert_eq!(parse_semester(arg, &(1..=8)), Err("Семестр, епта, от 1 до 8, если кто не знал".to_string()), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn parse_semester_explains_garbage() {
+        assert!(parse_semester("", &(1..=8)).unwrap_err().starts_with("Надо указать"));
+        assert!(parse_semester("седьмой", &(1..=8)).unwrap_err().contains("седьмой"));
+        assert!(parse_semester("7.5", &(1..=8)).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Clone, Debug)]
+pub(crate) struct ScrapeLimits {
+    pub(crate) max_concurrent: usize,
+    pub(crate) requests_per_sec: u32,
+    pub(crate) max_retries: u32
+}
+
+// Hands out evenly spaced request slots, callers wait for their turn instead of bursting.
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    interval: Duration,
+    next_slot: Arc<tokio::sync::Mutex<Instant>>
+}
+
+impl RateLimiter {
+    pub(crate) fn new(requests_per_sec: u32) -> RateLimiter {
+        RateLimiter {
+            interval: Duration::from_secs(1) / requests_per_sec.max(1),
+            next_slot: Arc::new(tokio::sync::Mutex::new(Instant::now()))
+        }
+    }
+
+    pub(crate) async fn wait(&self) {
+        let mut next_slot = self.next_slot.lock().await;
+        tokio::time::sleep_until(*next_slot).await;
+        *next_slot = Instant::now().max(*next_slot) + self.interval;
+    }
+}
+
+const BACKOFF_BASE: Duration = Duration::from_secs(2);
+const BACKOFF_MAX: Duration = Duration::from_secs(120);
+
+// Exponential backoff with full jitter, attempt starts at 0.
+pub(crate) fn backoff(attempt: u32) -> Duration {
+    let ceiling = BACKOFF_BASE.saturating_mul(2u32.saturating_pow(attempt)).min(BACKOFF_MAX);
+    ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_spaces_out_slots() {
+        let limiter = RateLimiter::new(4);
+        let start = Instant::now();
+
+        let mut slots = vec![];
+        for _ in 0..3 {
+            limiter.wait().await;
+            slots.push(start.elapsed());
+        }
+        assert_eq!(slots, [Duration::ZERO, Duration::from_millis(250), Duration::from_millis(500)]);
+
+        // A limiter left idle doesn't hand out a burst of slots that were missed
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let idle_end = Instant::now();
+        limiter.wait().await;
+        limiter.wait().await;
+        assert_eq!(idle_end.elapsed(), Duration::from_millis(250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clones_share_slots() {
+        let limiter = RateLimiter::new(2);
+        let start = Instant::now();
+        limiter.wait().await;
+        limiter.clone().wait().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn backoff_stays_under_ceiling() {
+        for attempt in [0, 1, 5, 6, 7, 31, 32, 100, u32::MAX] {
+            for _ in 0..100 {
+                let delay = backoff(attempt);
+                assert!(delay <= BACKOFF_MAX);
+                assert!(delay <= BACKOFF_BASE.saturating_mul(2u32.saturating_pow(attempt)));
+            }
+        }
+    }
+}
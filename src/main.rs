@@ -1,15 +1,27 @@
 use dotenv::dotenv;
+use std::sync::Arc;
 use teloxide::{
     prelude::*,
+    adaptors::throttle::Limits,
+    dispatching::dialogue::InMemStorage,
     types::{Update, UserId},
     utils::command::BotCommands,
 };
 
+mod config;
+mod crypto;
 mod db;
+mod dispatch;
 mod handlers;
+mod limit;
 mod maintain;
+mod mock;
+mod prefs;
 mod rating;
+mod rea;
 mod tg;
+#[cfg(test)]
+mod testing;
 
 #[derive(BotCommands, Clone)]
 #[command(rename_rule = "lowercase", description = "Список досутпных команд:")]
@@ -18,71 +30,144 @@ enum Command {
     Start,
     #[command(description = "Отобразить этот текст")]
     Help,
-    #[command(
-        description = "Установить логин и пароль (/logininfo login password)",
-        parse_with = "split"
-    )]
-    LoginInfo { username: String, pwd: String },
+    #[command(description = "Установить логин и пароль")]
+    LoginInfo { credentials: String },
     #[command(description = "Установить номер семестра (/setsemester 7)")]
-    SetSemester { semester: i64 },
-    #[command(description = "Получить рейтинг по всем предметам")]
-    GetRating,
+    SetSemester { semester: String },
+    #[command(description = "Получить рейтинг по всем предметам (/getrating 5 за прошлый семестр)")]
+    GetRating { semester: String },
+    #[command(description = "История изменений по предмету за текущий семестр (/history экономика)")]
+    History { subject: String },
+    #[command(description = "Какие изменения присылать и когда")]
+    Settings,
+    #[command(description = "Забыть логин и пароль, рейтинг больше не будет проверяться")]
+    Logout,
+    #[command(description = "Удалить все мои данные")]
+    DeleteMe,
     #[command(description = "для одмина")]
     Stats,
 }
 
 #[derive(Clone)]
 struct Config {
-    bot_maintainer: UserId,
+    bot_maintainers: Vec<UserId>,
     conn: sqlx::Pool<sqlx::Sqlite>,
+    cipher: crypto::Cipher,
+    semesters: std::ops::RangeInclusive<u8>,
+    source: Arc<dyn rating::RatingSource>,
 }
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    let bot = Bot::from_env();
-    let db_url = "sqlite:danke.db";
+
+    if std::env::args().nth(1).as_deref() == Some("gen-key") {
+        println!("{}", crypto::Cipher::generate_key());
+        return;
+    }
+
+    let settings = match config::Settings::load() {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
 
     simple_logger::SimpleLogger::new()
-        .with_level(log::LevelFilter::Warn)
+        .with_level(settings.log_level)
         .init()
         .unwrap();
 
-    let conn = sqlx::sqlite::SqlitePoolOptions::new()
-        .max_connections(10)
-        .connect(db_url)
-        .await
-        .unwrap();
+    let cipher = crypto::Cipher::from_env(crypto::KEY_VAR).unwrap();
 
-    let config = Config {
-        bot_maintainer: UserId(434585640),
-        conn,
+    let conn = db::connect(&settings.db_url, settings.max_connections).await.unwrap();
+
+    match db::migrate(&conn).await {
+        Ok(()) => (),
+        Err(sqlx::migrate::MigrateError::VersionMissing(version)) => {
+            log::error!("Database was migrated to version {} by a newer build, refusing to start", version);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            log::error!("Couldn't migrate the database: {}", err);
+            std::process::exit(1);
+        }
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("rotate-key") {
+        let new_cipher = crypto::Cipher::from_env(crypto::NEW_KEY_VAR).unwrap();
+        match db::rotate_key(&conn, &cipher, &new_cipher).await {
+            Ok(rotated) => println!("Re-encrypted {} passwords, now set {} to the value of {}", rotated, crypto::KEY_VAR, crypto::NEW_KEY_VAR),
+            Err(err) => println!("Key rotation failed, nothing was changed: {}", err),
+        }
+        return;
+    }
+
+    match db::encrypt_plaintext_passwords(&conn, &cipher).await {
+        Ok(0) => (),
+        Ok(encrypted) => log::warn!("Encrypted {} plaintext passwords", encrypted),
+        Err(err) => panic!("Couldn't encrypt plaintext passwords: {}", err),
+    }
+
+    let bot = Bot::from_env().throttle(Limits::default());
+
+    let source: Arc<dyn rating::RatingSource> = match &settings.portal {
+        config::PortalSettings::Mock { dir } => Arc::new(mock::MockSource::from_dir(dir).unwrap()),
+        config::PortalSettings::Rea { url } => {
+            Arc::new(rea::ReaPortal::new(url, limit::RateLimiter::new(settings.scrape_limits.requests_per_sec)))
+        }
     };
 
-    let update_sleep_secs: u64 = 1200;
-    let failed_update_sleep_secs: u64 = 600;
+    let config = Config {
+        bot_maintainers: settings.maintainers.clone(),
+        conn: conn.clone(),
+        cipher: cipher.clone(),
+        semesters: settings.semesters.clone(),
+        source: source.clone(),
+    };
 
+    let queued = Arc::new(tokio::sync::Notify::new());
+    tokio::spawn(dispatch::run_dispatcher(conn.clone(), bot.clone(), queued.clone()));
     tokio::spawn(async move {
-        maintain::run_updates(update_sleep_secs, failed_update_sleep_secs, db_url).await
+        maintain::run_updates(conn, queued, settings.updates, settings.scrape_limits, settings.semesters, cipher, source).await
     });
 
     let inline_query_handler =
         Update::filter_inline_query().branch(dptree::endpoint(handlers::inline_query_handler));
 
     let message_handler = Update::filter_message()
+        .inspect_async(|cfg: Config, msg: Message| async move { handlers::reactivate_chat(&cfg, msg.chat.id).await })
+        .enter_dialogue::<Message, InMemStorage<handlers::LoginState>, handlers::LoginState>()
         .branch(
             dptree::entry()
                 .filter_command::<Command>()
                 .endpoint(handlers::commands_handler),
         )
-        .branch(dptree::endpoint(|msg: Message, bot: Bot| async move {
+        // A password may start with a slash too, it has to be taken (and deleted) before it's called a bad command
+        .branch(dptree::case![handlers::LoginState::ReceivePassword { username }].endpoint(handlers::receive_password))
+        .branch(
+            dptree::filter(|msg: Message| msg.text().is_some_and(|text| text.starts_with('/')))
+                .endpoint(handlers::bad_command_handler),
+        )
+        .branch(dptree::case![handlers::LoginState::ReceiveLogin].endpoint(handlers::receive_login))
+        .branch(dptree::endpoint(|msg: Message, bot: tg::TgBot| async move {
             bot.send_message(msg.chat.id, "😑").await?;
             respond(())
         }));
 
+    let callback_query_handler = Update::filter_callback_query()
+        .inspect_async(|cfg: Config, q: CallbackQuery| async move {
+            if let Some(message) = q.message {
+                handlers::reactivate_chat(&cfg, message.chat.id).await;
+            }
+        })
+        .branch(dptree::endpoint(handlers::callback_query_handler));
+
     let schema = dptree::entry()
         .branch(message_handler)
-        .branch(inline_query_handler);
+        .branch(inline_query_handler)
+        .branch(callback_query_handler);
 
     Dispatcher::builder(bot, schema)
         .default_handler(|upd| async move {
@@ -91,7 +176,7 @@ async fn main() {
         .error_handler(LoggingErrorHandler::with_custom_text(
             "An error has occurred in the dispatcher",
         ))
-        .dependencies(dptree::deps![config])
+        .dependencies(dptree::deps![config, InMemStorage::<handlers::LoginState>::new()])
         .enable_ctrlc_handler()
         .build()
         .dispatch()
@@ -1,162 +1,439 @@
+use crate::config;
+use crate::crypto;
 use crate::db;
+use crate::limit;
+use crate::prefs;
 use crate::rating;
 use std::collections::HashMap;
-use crate::rating::Rating;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::rating::{Rating, RatingError, RatingSource, Subject};
 use teloxide::utils::markdown;
 
-#[derive(Debug)]
-struct Notification {
-    pub(crate) chat_id: i64,
-    pub(crate) message: String
+#[derive(Clone)]
+struct Scraper {
+    source: Arc<dyn RatingSource>,
+    cipher: crypto::Cipher,
+    permits: Arc<tokio::sync::Semaphore>,
+    max_retries: u32,
+    semesters: RangeInclusive<u8>
 }
 
-async fn get_differences(conn: &sqlx::Pool<sqlx::Sqlite>) -> Option<Vec<Notification>> {
-    let users = sqlx::query_as::<_, db::User>("SELECT * FROM users where not(pwd is null or pwd = '' or username is null or username = '' or semester is null or semester = 0)")
-    .fetch_all(conn)
-    .await;
-    if users.is_err() { 
-        log::error!("Couldn't get users");
-        return None; 
+async fn fetch_rating(conn: sqlx::Pool<sqlx::Sqlite>, user: db::User, scraper: Scraper, delay: Duration) -> Result<Rating, RatingError> {
+    tokio::time::sleep(delay).await;
+    let _permit = scraper.permits.acquire().await.unwrap();
+
+    let mut attempt = 0;
+    let res = loop {
+        let res = rating::get_rating(scraper.source.as_ref(), user.clone(), &scraper.cipher, &scraper.semesters).await;
+        match res {
+            Err(RatingError::Network(err)) if attempt < scraper.max_retries => {
+                let backoff = limit::backoff(attempt);
+                log::info!("Network error for user {} ({}), retrying in {:?}", user.id, err, backoff);
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            res => break res
+        }
+    };
+
+    match &res {
+        Ok(_) => {
+            if user.auth_failures > 0 {
+                if let Err(err) = db::reset_auth_failures(&conn, &user).await {
+                    log::error!("Couldn't reset auth failures of user {}: {}", user.id, err);
+                }
+            }
+        }
+        Err(RatingError::BadCredentials) => {
+            log::info!("Bad credentials of user {}", user.id);
+            if let Err(err) = db::record_auth_failure(&conn, &user).await {
+                log::error!("Couldn't record auth failure of user {}: {}", user.id, err);
+            }
+        }
+        Err(err @ (RatingError::PortalLayoutChanged { .. } | RatingError::ParseValue { .. })) => {
+            log::error!("Couldn't parse rating of user {}: {}", user.id, err);
+        }
+        Err(err) => {
+            log::warn!("Couldn't get rating of user {}: {}", user.id, err);
+        }
+    }
+    res
+}
+
+// Scrapes are spread evenly over `spread` instead of all starting at once.
+// Each rating is saved as soon as its scrape finishes, the notifications are queued along with the changes
+// and `queued` is notified right away. None if the users to poll couldn't be loaded.
+async fn get_differences(conn: &sqlx::Pool<sqlx::Sqlite>, scraper: &Scraper, spread: Duration, max_auth_failures: i64, queued: &tokio::sync::Notify) -> Option<db::CycleSummary> {
+    let users = match db::get_pollable_users(conn, max_auth_failures).await {
+        Ok(users) => users,
+        Err(err) => {
+            log::error!("Couldn't get users to poll: {}", err);
+            return None;
+        }
+    };
+
+    let users_count = users.len() as u32;
+    let mut set: tokio::task::JoinSet<Result<Rating, RatingError>> = tokio::task::JoinSet::new();  
+    for (user_num, user) in users.into_iter().enumerate() {
+        let delay = spread * user_num as u32 / users_count;
+        set.spawn(fetch_rating(conn.clone(), user, scraper.clone(), delay));
     }
-    let users = users.unwrap();
 
-    let mut set: tokio::task::JoinSet<Option<Rating>> = tokio::task::JoinSet::new();  
-    for user in users {
-        set.spawn(rating::get_rating(user));
+    let mut summary = db::CycleSummary::default();
+    while let Some(res) = set.join_next().await {
+        match res {
+            Ok(Ok(rating)) => {
+                let user_id = rating.user.id;
+                match apply_rating(conn, rating).await {
+                    Ok(()) => {
+                        summary.succeeded += 1;
+                        queued.notify_one();
+                    }
+                    Err(err) => {
+                        summary.failed += 1;
+                        log::error!("Couldn't save new rating of user {}: {}", user_id, err);
+                    }
+                }
+            }
+            Ok(Err(RatingError::BadCredentials)) => summary.auth_failed += 1,
+            Ok(Err(RatingError::PortalLayoutChanged { .. } | RatingError::ParseValue { .. })) => summary.parse_failed += 1,
+            Ok(Err(_)) => summary.failed += 1,
+            Err(err) => {
+                log::error!("Rating task failed: {}", err);
+                summary.failed += 1;
+            }
+        }
     }
 
-    let mut new_ratings:Vec<Rating> = vec![];
-    while let Some(Ok(Some(rating))) = set.join_next().await {
-        new_ratings.push(rating);
+    if let Err(err) = queue_auth_failure_notices(conn, max_auth_failures).await {
+        log::error!("Couldn't queue auth failure notices: {}", err);
     }
 
-    if new_ratings.is_empty() {
-        log::warn!("Couldn't get any new ratings");
-        return None;
+    log::info!("Update cycle finished: {}", summary);
+    if let Err(err) = db::insert_cycle_summary(conn, &summary).await {
+        log::error!("Couldn't save cycle summary: {}", err);
     }
 
-    let mut notifications: Vec<Notification> = vec![];  
-    for rating in new_ratings {
-        let db_rating_map = db::get_rating_map(conn, &rating.user).await;
-        if db_rating_map.is_none() {
-            log::error!("Couldn't get rating map");
-            return None;
+    Some(summary)
+}
+
+async fn queue_auth_failure_notices(conn: &sqlx::Pool<sqlx::Sqlite>, max_auth_failures: i64) -> Result<(), db::DbError> {
+    let now = prefs::unix_now();
+    let mut tx = conn.begin().await?;
+    for user in db::take_auth_failure_notices(&mut tx, max_auth_failures).await? {
+        let preferences = db::get_preferences(&mut tx, &user).await?;
+        let message = markdown::escape("Не получается войти на портал с твоими логином и паролем, обнови их через /logininfo. Пока не обновишь, рейтинг проверяться не будет");
+        db::queue_notification(&mut tx, &user, &message, preferences.quiet_until(now).unwrap_or(0)).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+// Diffs the new rating against the stored one and saves it in a single transaction,
+// so a failure leaves the stored rating as it was and the diff is found again next time.
+// The notifications about the changes are queued in the same transaction, as the user's preferences say.
+async fn apply_rating(conn: &sqlx::Pool<sqlx::Sqlite>, rating: Rating) -> Result<(), db::DbError> {
+    let mut tx = conn.begin().await?;
+
+    let db_rating_map = db::get_rating_map(&mut tx, &rating.user, rating.semester).await?;
+    let preferences = db::get_preferences(&mut tx, &rating.user).await?;
+
+    let mut notifications: Vec<String> = vec![];
+    if rating.user.semester == 0 {
+        db::set_semester(&mut tx, &rating.user, rating.semester).await?;
+    }
+    else if let Some(latest_semester) = rating.latest_semester.filter(|latest| *latest > rating.semester) {
+        if db::offer_semester(&mut tx, &rating.user, latest_semester).await? {
+            notifications.push(markdown::escape(&format!("Появился {} семестр, переключиться на него: /setsemester {}", latest_semester, latest_semester)));
         }
-        let db_rating_map = db_rating_map.unwrap();
-        
-        if db_rating_map.is_empty() {
-            for subject in rating.subjects {
-                let rating_id = sqlx::query!("INSERT into rating (user_id, subject_name, attendance, control, creative, test) values (?, ?, ?, ?, ?, ?)", 
-                    rating.user.id, subject.name, subject.attendance, subject.control, subject.creative, subject.test)
-                .execute(conn)
-                .await;
-
-                if rating_id.is_err() {
-                    log::error!("Couldn't insert into rating");
-                    return None; 
-                }
-            }
-            notifications.push(Notification { chat_id: rating.user.chat_id, message: markdown::escape("Рейтинг обновился (вероятно это уведомление из-за смены семестра)") });
-        }
-        else {
-            let mut message: Vec<String> = vec![];
-            for subject in rating.subjects {
-                if db_rating_map.contains_key(&subject.name) {
-                    let db_subject = db_rating_map.get(&subject.name).unwrap();
-                    
-                    let mut change: bool = false;
-                    if subject.attendance != db_subject.attendance {
-                        message.push(format!("||{}|| за посещение", markdown::escape(&(subject.attendance - db_subject.attendance).to_string())));
-                        change = true;
-                    }
-                    if subject.creative != db_subject.creative {
-                        message.push(format!("||{}|| по творческому", markdown::escape(&(subject.creative - db_subject.creative).to_string())));
-                        change = true;
-                    }
-                    if subject.control != db_subject.control {
-                        message.push(format!("||{}|| за контрольный", markdown::escape(&(subject.control - db_subject.control).to_string())));
-                        change = true;
-                    }
-                    if subject.test != db_subject.test {
-                        message.push(format!("||{}|| за экз/тест", markdown::escape(&(subject.test - db_subject.test).to_string())));
-                        change = true;
-                    }
+    }
 
-                    if change {
-                        let rating_id = sqlx::query!("update rating set attendance = ?, control = ?, creative = ?, test = ? where user_id = ? and subject_name = ?", 
-                        subject.attendance, subject.control, subject.creative, subject.test, rating.user.id, subject.name)
-                        .execute(conn)
-                        .await;
+    if db_rating_map.is_empty() {
+        for subject in rating.subjects {
+            db::upsert_subject(&mut tx, &rating.user, rating.semester, &subject).await?;
 
-                        if rating_id.is_err() {
-                            log::error!("Couldn't update rating");
-                            return None; 
-                        }
+            for (component, value) in subject.components() {
+                db::insert_history(&mut tx, &rating.user, rating.semester, &subject.name, component, None, value).await?;
+            }
+        }
+        notifications.push(markdown::escape(&format!("Загрузил рейтинг за {} семестр, дальше буду присылать изменения", rating.semester)));
+    }
+    else {
+        let mut new_subjects: Vec<String> = vec![];
+        let mut message: Vec<String> = vec![];
+        for subject in rating.subjects {
+            if let Some(db_subject) = db_rating_map.get(&subject.name) {
+                let mut change: bool = false;
+                let mut lines: Vec<String> = vec![];
+                for ((component, new_value), (_, old_value)) in subject.components().into_iter().zip(db_subject.components()) {
+                    if new_value == old_value {
+                        continue;
+                    }
+                    change = true;
+                    db::insert_history(&mut tx, &rating.user, rating.semester, &subject.name, component, Some(old_value), new_value).await?;
 
-                        message.push(format!("По {}\n", markdown::escape(&(subject.name).to_string())));
-                    } 
-                }
-                else {
-                    let rating_id = sqlx::query!("insert into rating (user_id, subject_name, attendance, control, creative, test) values (?, ?, ?, ?, ?, ?)", 
-                        rating.user.id, subject.name, subject.attendance, subject.control, subject.creative, subject.test)
-                    .execute(conn)
-                    .await;
-
-                    if rating_id.is_err() {
-                        log::error!("Couldn't insert rating");
-                        return None; 
+                    if preferences.notifies(component) {
+                        lines.push(format!("||{}|| {}", markdown::escape(&(new_value - old_value).to_string()), rating::component_change(component)));
                     }
+                }
 
-                    message.insert(0, format!("Появился новый предмет:\n{}", markdown::escape(&subject.to_string())))
+                if change {
+                    db::upsert_subject(&mut tx, &rating.user, rating.semester, &subject).await?;
+                }
+                if !lines.is_empty() {
+                    message.extend(lines);
+                    message.push(format!("По {}\n", markdown::escape(&(subject.name).to_string())));
                 }
             }
-            if !message.is_empty() {
-                notifications.push(Notification { chat_id: rating.user.chat_id, message: message.join("\n") });
+            else {
+                db::upsert_subject(&mut tx, &rating.user, rating.semester, &subject).await?;
+
+                for (component, value) in subject.components() {
+                    db::insert_history(&mut tx, &rating.user, rating.semester, &subject.name, component, None, value).await?;
+                }
+
+                new_subjects.push(format!("Появился новый предмет:\n{}", markdown::escape(&subject.to_string())));
             }
         }
-    };
 
-    Some(notifications)
+        // Digest users get the changes later, new subjects are told about right away
+        if preferences.delivery != prefs::Delivery::Instant {
+            message.clear();
+        }
+        new_subjects.extend(message);
+        if !new_subjects.is_empty() {
+            notifications.push(new_subjects.join("\n"));
+        }
+    }
+
+    let not_before = preferences.quiet_until(prefs::unix_now()).unwrap_or(0);
+    for message in notifications {
+        db::queue_notification(&mut tx, &rating.user, &message, not_before).await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
 }
 
-pub(crate) async fn run_updates(update_sleep_secs: u64, failed_update_sleep_secs: u64, db_url: &str) {
-    let conn = sqlx::sqlite::SqlitePoolOptions::new()
-    .max_connections(1)
-    .connect(db_url)
-    .await
-    .unwrap();
+// Net change of a component over the digest period
+struct Delta<'a> {
+    component: &'a str,
+    old_value: f32,
+    new_value: f32
+}
 
-    let client = reqwest::Client::new();
-    let mut data = HashMap::with_capacity(3);
-    data.insert("parse_mode", "MarkdownV2".to_string());
+// Formats the changes as one message, per subject with the net change of every component.
+fn format_digest(changes: &[db::Change], totals: &HashMap<String, Subject>, preferences: &prefs::Preferences) -> Option<String> {
+    // Subjects in the order they first changed
+    let mut subjects: Vec<(&str, Vec<Delta>)> = vec![];
+    for change in changes {
+        let subject = match subjects.iter().position(|(name, _)| *name == change.subject_name) {
+            Some(subject) => subject,
+            None => {
+                subjects.push((&change.subject_name, vec![]));
+                subjects.len() - 1
+            }
+        };
 
-    let send_message_url = format!("https://api.telegram.org/bot{}/sendMessage", std::env::var("TELOXIDE_TOKEN").unwrap());
+        let deltas = &mut subjects[subject].1;
+        match deltas.iter_mut().find(|delta| delta.component == change.component) {
+            Some(delta) => delta.new_value = change.new_value,
+            None => deltas.push(Delta { component: &change.component, old_value: change.old_value, new_value: change.new_value })
+        }
+    }
 
-    loop {
-        let notifications = get_differences(&conn).await;
-        if notifications.is_none() { 
-            log::warn!("Notifications returned with None"); 
-            tokio::time::sleep(std::time::Duration::from_secs(failed_update_sleep_secs)).await;
+    let mut message: Vec<String> = vec![];
+    for (subject, deltas) in subjects {
+        let lines: Vec<String> = deltas.iter()
+            .filter(|delta| preferences.notifies(delta.component) && delta.old_value != delta.new_value)
+            .map(|delta| format!("||{}|| {}", markdown::escape(&(delta.new_value - delta.old_value).to_string()), rating::component_change(delta.component)))
+            .collect();
+        if lines.is_empty() {
             continue;
         }
-        
-        let mut set: tokio::task::JoinSet<Result<reqwest::Response, reqwest::Error>> = tokio::task::JoinSet::new();  
-        for notification in notifications.unwrap() {
-            let mut data = data.clone();
-            data.insert("chat_id", notification.chat_id.to_string());
-            data.insert("text", notification.message);
 
-            set.spawn( client.post(&send_message_url)
-                        .json(&data)
-                        .send());
+        message.extend(lines);
+        // The total is left out for subjects that disappeared from the rating since
+        match totals.get(subject) {
+            Some(total) => {
+                let total: f32 = total.components().iter().map(|(_, value)| value).sum();
+                message.push(markdown::escape(&format!("По {}, теперь всего {}\n", subject, total)));
+            },
+            None => message.push(format!("По {}\n", markdown::escape(subject)))
         }
+    }
 
-        while let Some(res) = set.join_next().await {
-            if res.is_err() {
-                log::error!("error while sending notification: {}", res.err().unwrap().to_string())
-            }
+    if message.is_empty() {
+        return None;
+    }
+    let header = match preferences.delivery {
+        prefs::Delivery::Weekly => "Изменения за неделю:",
+        _ => "Изменения за день:"
+    };
+    Some(format!("{}\n{}", markdown::escape(header), message.join("\n")))
+}
+
+// Queues the digests that are due, each along with moving the user's last digest time.
+async fn queue_digests(conn: &sqlx::Pool<sqlx::Sqlite>, now: i64) -> Result<(), db::DbError> {
+    for user in db::get_digest_users(conn).await? {
+        let mut tx = conn.begin().await?;
+        let mut preferences = db::get_preferences(&mut tx, &user).await?;
+        if !preferences.digest_due(now) {
+            continue;
+        }
+
+        let changes = db::get_changes_since(&mut tx, &user, user.semester, preferences.last_digest_at).await?;
+        let totals = db::get_rating_map(&mut tx, &user, user.semester).await?;
+        if let Some(message) = format_digest(&changes, &totals, &preferences) {
+            db::queue_notification(&mut tx, &user, &message, preferences.quiet_until(now).unwrap_or(0)).await?;
+        }
+
+        preferences.last_digest_at = now;
+        db::save_preferences(&mut tx, &user, &preferences).await?;
+        tx.commit().await?;
+    }
+    Ok(())
+}
+
+// After `max_auth_failures` failed logins in a row the user is asked to update their credentials
+// and isn't polled anymore until they do. `queued` is notified when a cycle may have queued notifications.
+pub(crate) async fn run_updates(conn: sqlx::Pool<sqlx::Sqlite>, queued: Arc<tokio::sync::Notify>, settings: config::UpdateSettings, limits: limit::ScrapeLimits, semesters: RangeInclusive<u8>, cipher: crypto::Cipher, source: Arc<dyn RatingSource>) {
+    let scraper = Scraper {
+        source,
+        cipher,
+        permits: Arc::new(tokio::sync::Semaphore::new(limits.max_concurrent)),
+        max_retries: limits.max_retries,
+        semesters
+    };
+    let update_interval = Duration::from_secs(settings.interval_secs);
+    let failed_interval = Duration::from_secs(settings.failed_interval_secs);
+
+    // Polling takes up most of the interval now, only the rest of it is slept through.
+    // After a failed cycle the next one is due sooner and its scrapes are spread over the shorter interval.
+    let mut interval = update_interval;
+    loop {
+        let cycle_start = Instant::now();
+        let summary = get_differences(&conn, &scraper, interval, settings.max_auth_failures, &queued).await;
+        if let Err(err) = queue_digests(&conn, prefs::unix_now()).await {
+            log::error!("Couldn't queue digests: {}", err);
         }
+        queued.notify_one();
+
+        let failed = match &summary {
+            Some(summary) => summary.succeeded == 0 && summary.total() > 0,
+            None => true
+        };
+        interval = if failed {
+            log::warn!("Couldn't get any new ratings");
+            failed_interval
+        } else {
+            update_interval
+        };
 
-        tokio::time::sleep(std::time::Duration::from_secs(update_sleep_secs)).await;
+        tokio::time::sleep(interval.saturating_sub(cycle_start.elapsed())).await;
     }   
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    fn test_rating(user: db::User, attendance: f32) -> Rating {
+        Rating {
+            user,
+            semester: 7,
+            latest_semester: Some(7),
+            subjects: vec![Subject { name: "Экономика".to_string(), attendance, control: 0.0, creative: 0.0, test: 0.0 }]
+        }
+    }
+
+    #[tokio::test]
+    async fn apply_rating_queues_notifications_with_changes() {
+        let conn = test_db().await;
+        let user = db::create_user(&conn, 42).await.unwrap();
+
+        apply_rating(&conn, test_rating(user.clone(), 10.0)).await.unwrap();
+        let user = db::find_user(&conn, 42).await.unwrap();
+        assert_eq!(user.semester, 7);
+
+        apply_rating(&conn, test_rating(user.clone(), 12.0)).await.unwrap();
+        apply_rating(&conn, test_rating(user.clone(), 12.0)).await.unwrap();
+
+        let queued = db::get_due_notifications(&conn, i64::MAX, 10).await.unwrap();
+        assert_eq!(queued.len(), 2);
+        assert!(queued[0].message.starts_with("Загрузил рейтинг за 7 семестр"));
+        assert!(queued[1].message.contains("Экономика"));
+        assert_eq!(db::get_rating(&conn, &user, 7).await.unwrap()[0].attendance, 12.0);
+    }
+
+    #[tokio::test]
+    async fn auth_failures_are_noticed_once_and_stop_polling() {
+        let conn = test_db().await;
+        let mut user = db::create_user(&conn, 42).await.unwrap();
+        user.username = "student".to_string();
+        user.pwd = "secret".to_string();
+        db::sync_user(&conn, &user).await.unwrap();
+        let pollable = |users: Vec<db::User>| users.iter().any(|polled| polled.id == user.id);
+
+        for _ in 0..3 {
+            assert!(pollable(db::get_pollable_users(&conn, 3).await.unwrap()));
+            db::record_auth_failure(&conn, &user).await.unwrap();
+        }
+        assert!(!pollable(db::get_pollable_users(&conn, 3).await.unwrap()));
+
+        // The notice is queued on the next cycle and not repeated on the ones after it
+        for _ in 0..2 {
+            queue_auth_failure_notices(&conn, 3).await.unwrap();
+            assert_eq!(db::get_due_notifications(&conn, i64::MAX, 10).await.unwrap().len(), 1);
+        }
+
+        // New credentials from /logininfo reset the counter and the user is polled again
+        db::reset_auth_failures(&conn, &user).await.unwrap();
+        assert!(pollable(db::get_pollable_users(&conn, 3).await.unwrap()));
+        for _ in 0..3 {
+            db::record_auth_failure(&conn, &user).await.unwrap();
+        }
+        queue_auth_failure_notices(&conn, 3).await.unwrap();
+        assert_eq!(db::get_due_notifications(&conn, i64::MAX, 10).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn apply_rating_respects_preferences() {
+        let conn = test_db().await;
+        let user = db::create_user(&conn, 42).await.unwrap();
+        apply_rating(&conn, test_rating(user.clone(), 10.0)).await.unwrap();
+        let user = db::find_user(&conn, 42).await.unwrap();
+
+        let mut preferences = prefs::Preferences::default();
+        preferences.toggle("attendance");
+        db::save_preferences(&conn, &user, &preferences).await.unwrap();
+        apply_rating(&conn, test_rating(user.clone(), 12.0)).await.unwrap();
+
+        preferences.toggle("attendance");
+        preferences.next_delivery(0);
+        db::save_preferences(&conn, &user, &preferences).await.unwrap();
+        apply_rating(&conn, test_rating(user.clone(), 14.0)).await.unwrap();
+
+        // Only the first load was worth a message, the changes are kept in the history
+        assert_eq!(db::get_due_notifications(&conn, i64::MAX, 10).await.unwrap().len(), 1);
+        assert_eq!(db::get_history(&conn, &user, 7, "Экономика").await.unwrap().len(), 6);
+
+        let mut tx = conn.begin().await.unwrap();
+        let changes = db::get_changes_since(&mut tx, &user, 7, 0).await.unwrap();
+        let totals = db::get_rating_map(&mut tx, &user, 7).await.unwrap();
+        let digest = format_digest(&changes, &totals, &preferences).unwrap();
+        assert!(digest.starts_with("Изменения за день:"));
+        assert!(digest.contains("||4|| за посещение"));
+        assert!(digest.contains("По Экономика, теперь всего 14"));
+        assert!(format_digest(&changes, &HashMap::new(), &preferences).unwrap().contains("По Экономика\n"));
+
+        preferences.next_delivery(0);
+        assert!(format_digest(&changes, &totals, &preferences).unwrap().starts_with("Изменения за неделю:"));
+
+        preferences.toggle("attendance");
+        assert_eq!(format_digest(&changes, &totals, &preferences), None);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::db::User;
+use crate::rating::{RatingError, RatingSource, Subject};
+use crate::rea;
+use std::collections::HashMap;
+use std::path::Path;
+
+// Serves recorded rating pages instead of talking to a real portal,
+// for tests and for staging deployments.
+#[derive(Default)]
+pub(crate) struct MockSource {
+    pwd: Option<String>,
+    pages: HashMap<u8, String>
+}
+
+impl MockSource {
+    // Every file named like `7.html` in the directory is served as the rating page of that semester.
+    pub(crate) fn from_dir(dir: &Path) -> std::io::Result<MockSource> {
+        let mut source = MockSource::default();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("html") { continue; }
+
+            let semester = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u8>().ok());
+            if let Some(semester) = semester {
+                source.pages.insert(semester, std::fs::read_to_string(&path)?);
+            }
+        }
+        Ok(source)
+    }
+
+    // Without a password any credentials are accepted.
+    #[cfg(test)]
+    pub(crate) fn with_password(mut self, pwd: &str) -> MockSource {
+        self.pwd = Some(pwd.to_string());
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_semester(mut self, semester: u8, page: &str) -> MockSource {
+        self.pages.insert(semester, page.to_string());
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl RatingSource for MockSource {
+    async fn authenticate(&self, _user: &User, pwd: &str) -> Result<(), RatingError> {
+        match &self.pwd {
+            Some(expected) if expected != pwd => Err(RatingError::BadCredentials),
+            _ => Ok(())
+        }
+    }
+
+    // Without a semester the latest one is served, like the portal does.
+    async fn fetch_semester(&self, _user: &User, semester: Option<u8>) -> Result<String, RatingError> {
+        let semester = semester.or(self.pages.keys().max().copied()).ok_or(RatingError::SemesterUnavailable)?;
+        self.pages.get(&semester).cloned().ok_or(RatingError::SemesterUnavailable)
+    }
+
+    fn parse_subjects(&self, page: &str) -> Result<Vec<Subject>, RatingError> {
+        rea::parse_subjects(page)
+    }
+
+    // Recorded pages may list other semesters than the ones that are served.
+    fn parse_semesters(&self, _page: &str) -> Vec<u8> {
+        let mut semesters: Vec<u8> = self.pages.keys().copied().collect();
+        semesters.sort_unstable();
+        semesters
+    }
+
+    // There are no sessions to drop.
+    fn forget(&self, _user: &User) {}
+}
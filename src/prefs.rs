@@ -0,0 +1,259 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Moscow doesn't switch to summer time, a fixed offset is enough
+const MSK_OFFSET_SECS: i64 = 3 * 3600;
+const DAY_SECS: i64 = 24 * 3600;
+const WEEK_SECS: i64 = 7 * DAY_SECS;
+
+// Quiet hours the settings button cycles through, (start, end) in Moscow time
+const QUIET_PRESETS: [Option<(u8, u8)>; 4] = [None, Some((23, 8)), Some((22, 9)), Some((0, 10))];
+// Hours a digest can be sent at, Moscow time
+const DIGEST_HOURS: [u8; 6] = [8, 12, 16, 18, 20, 22];
+
+pub(crate) fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs() as i64)
+}
+
+fn msk_hour(unix: i64) -> u8 {
+    ((unix + MSK_OFFSET_SECS).rem_euclid(DAY_SECS) / 3600) as u8
+}
+
+// Unix time of the Moscow midnight the day of `unix` started with.
+fn msk_day_start(unix: i64) -> i64 {
+    unix - (unix + MSK_OFFSET_SECS).rem_euclid(DAY_SECS)
+}
+
+// 0 is Monday, the epoch started on a Thursday.
+fn msk_weekday(unix: i64) -> u8 {
+    ((unix + MSK_OFFSET_SECS).div_euclid(DAY_SECS) + 3).rem_euclid(7) as u8
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, sqlx::Type)]
+#[sqlx(rename_all = "lowercase")]
+pub(crate) enum Delivery {
+    // A message per update cycle
+    Instant,
+    // Changes are collected into one message a day
+    Daily,
+    // Or a week
+    Weekly
+}
+
+#[derive(Clone, Debug, PartialEq, sqlx::FromRow)]
+pub(crate) struct Preferences {
+    pub(crate) attendance: bool,
+    pub(crate) creative: bool,
+    pub(crate) control: bool,
+    pub(crate) test: bool,
+    pub(crate) quiet_start: Option<u8>,
+    pub(crate) quiet_end: Option<u8>,
+    pub(crate) delivery: Delivery,
+    pub(crate) last_digest_at: i64,
+    // Digests go out at this hour in Moscow time, weekly ones on this weekday (0 is Monday)
+    pub(crate) digest_hour: u8,
+    pub(crate) digest_weekday: u8
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Preferences {
+            attendance: true,
+            creative: true,
+            control: true,
+            test: true,
+            quiet_start: None,
+            quiet_end: None,
+            delivery: Delivery::Instant,
+            last_digest_at: 0,
+            digest_hour: 20,
+            digest_weekday: 6
+        }
+    }
+}
+
+impl Preferences {
+    // Components are named as in rating::Subject::components
+    pub(crate) fn notifies(&self, component: &str) -> bool {
+        match component {
+            "attendance" => self.attendance,
+            "creative" => self.creative,
+            "control" => self.control,
+            "test" => self.test,
+            _ => true
+        }
+    }
+
+    pub(crate) fn toggle(&mut self, component: &str) {
+        match component {
+            "attendance" => self.attendance = !self.attendance,
+            "creative" => self.creative = !self.creative,
+            "control" => self.control = !self.control,
+            "test" => self.test = !self.test,
+            _ => ()
+        }
+    }
+
+    pub(crate) fn quiet_hours(&self) -> Option<(u8, u8)> {
+        self.quiet_start.zip(self.quiet_end)
+    }
+
+    pub(crate) fn next_quiet_hours(&mut self) {
+        let current = QUIET_PRESETS.iter().position(|preset| *preset == self.quiet_hours()).unwrap_or(0);
+        let next = QUIET_PRESETS[(current + 1) % QUIET_PRESETS.len()];
+        self.quiet_start = next.map(|(start, _)| start);
+        self.quiet_end = next.map(|(_, end)| end);
+    }
+
+    // Switching to the digest starts collecting changes from `now`.
+    pub(crate) fn next_delivery(&mut self, now: i64) {
+        self.delivery = match self.delivery {
+            Delivery::Instant => Delivery::Daily,
+            Delivery::Daily => Delivery::Weekly,
+            Delivery::Weekly => Delivery::Instant
+        };
+        if self.delivery == Delivery::Daily {
+            self.last_digest_at = now;
+        }
+    }
+
+    pub(crate) fn next_digest_hour(&mut self) {
+        let current = DIGEST_HOURS.iter().position(|hour| *hour == self.digest_hour).unwrap_or(0);
+        self.digest_hour = DIGEST_HOURS[(current + 1) % DIGEST_HOURS.len()];
+    }
+
+    pub(crate) fn next_digest_weekday(&mut self) {
+        self.digest_weekday = (self.digest_weekday + 1) % 7;
+    }
+
+    // When the quiet hours `now` falls into end, None outside of them.
+    pub(crate) fn quiet_until(&self, now: i64) -> Option<i64> {
+        let (start, end) = self.quiet_hours()?;
+        let hour = msk_hour(now);
+        let quiet = if start <= end { start <= hour && hour < end } else { hour >= start || hour < end };
+        if !quiet {
+            return None;
+        }
+
+        let end_today = msk_day_start(now) + end as i64 * 3600;
+        Some(if end_today > now { end_today } else { end_today + DAY_SECS })
+    }
+
+    // The latest time a digest was scheduled for up to `now`, None without digests.
+    fn last_digest_slot(&self, now: i64) -> Option<i64> {
+        let today = msk_day_start(now) + self.digest_hour as i64 * 3600;
+        match self.delivery {
+            Delivery::Instant => None,
+            Delivery::Daily => Some(if today <= now { today } else { today - DAY_SECS }),
+            Delivery::Weekly => {
+                let days_back = (msk_weekday(now) + 7 - self.digest_weekday) % 7;
+                let slot = today - days_back as i64 * DAY_SECS;
+                Some(if slot <= now { slot } else { slot - WEEK_SECS })
+            }
+        }
+    }
+
+    // A digest is due once its time has come and it hasn't been sent since.
+    pub(crate) fn digest_due(&self, now: i64) -> bool {
+        self.last_digest_slot(now).is_some_and(|slot| slot > self.last_digest_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2026-10-18 00:00 in Moscow
+    const MIDNIGHT: i64 = 1_792_270_800;
+
+    fn at(hour: i64) -> i64 {
+        MIDNIGHT + hour * 3600
+    }
+
+    #[test]
+    fn moscow_time() {
+        assert_eq!(msk_hour(MIDNIGHT), 0);
+        assert_eq!(msk_hour(at(23) + 59 * 60), 23);
+        assert_eq!(msk_day_start(at(15)), MIDNIGHT);
+    }
+
+    #[test]
+    fn quiet_hours_over_midnight() {
+        let mut prefs = Preferences::default();
+        assert_eq!(prefs.quiet_until(at(3)), None);
+
+        prefs.next_quiet_hours();
+        assert_eq!(prefs.quiet_hours(), Some((23, 8)));
+        assert_eq!(prefs.quiet_until(at(22)), None);
+        assert_eq!(prefs.quiet_until(at(23)), Some(at(24 + 8)));
+        assert_eq!(prefs.quiet_until(at(3)), Some(at(8)));
+        assert_eq!(prefs.quiet_until(at(8)), None);
+    }
+
+    #[test]
+    fn quiet_hours_presets_cycle() {
+        let mut prefs = Preferences::default();
+        for _ in 0..QUIET_PRESETS.len() {
+            prefs.next_quiet_hours();
+        }
+        assert_eq!(prefs.quiet_hours(), None);
+
+        prefs.quiet_start = Some(0);
+        prefs.quiet_end = Some(10);
+        assert_eq!(prefs.quiet_until(at(9)), Some(at(10)));
+    }
+
+    #[test]
+    fn digest_is_due_once_a_day() {
+        let mut prefs = Preferences::default();
+        assert!(!prefs.digest_due(at(21)));
+
+        prefs.next_delivery(at(10));
+        assert_eq!(prefs.delivery, Delivery::Daily);
+        assert!(!prefs.digest_due(at(19)));
+        assert!(prefs.digest_due(at(20)));
+
+        prefs.last_digest_at = at(20);
+        assert!(!prefs.digest_due(at(23)));
+        assert!(prefs.digest_due(at(24 + 20)));
+    }
+
+    #[test]
+    fn weekly_digest_on_chosen_day() {
+        // MIDNIGHT is a Sunday
+        assert_eq!(msk_weekday(MIDNIGHT), 6);
+        assert_eq!(msk_weekday(at(24)), 0);
+
+        let mut prefs = Preferences::default();
+        prefs.next_delivery(at(10));
+        prefs.next_delivery(at(10));
+        assert_eq!(prefs.delivery, Delivery::Weekly);
+        // Switching from daily to weekly keeps what's been collected so far
+        assert_eq!(prefs.last_digest_at, at(10));
+
+        prefs.next_digest_weekday();
+        prefs.next_digest_hour();
+        assert_eq!((prefs.digest_weekday, prefs.digest_hour), (0, 22));
+        assert!(!prefs.digest_due(at(23)));
+        assert!(!prefs.digest_due(at(24 + 21)));
+        assert!(prefs.digest_due(at(24 + 22)));
+
+        prefs.last_digest_at = at(24 + 22);
+        assert!(!prefs.digest_due(at(6 * 24)));
+        assert!(prefs.digest_due(at(8 * 24 + 22)));
+    }
+
+    #[test]
+    fn missed_daily_digest_is_caught_up() {
+        let mut prefs = Preferences::default();
+        prefs.next_delivery(at(10));
+        assert!(prefs.digest_due(at(24 + 5)));
+    }
+
+    #[test]
+    fn components_toggle() {
+        let mut prefs = Preferences::default();
+        prefs.toggle("attendance");
+        assert!(!prefs.notifies("attendance"));
+        assert!(prefs.notifies("test"));
+    }
+}
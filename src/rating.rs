@@ -1,9 +1,12 @@
+use crate::crypto;
 use crate::db::User;
-use log::warn;
-use scraper::{Html, Selector, ElementRef};
+use std::ops::RangeInclusive;
 
 pub(crate) struct Rating {
     pub(crate) user: User,
+    pub(crate) semester: u8,
+    // The newest semester the portal has, None if it doesn't list them
+    pub(crate) latest_semester: Option<u8>,
     pub(crate) subjects: Vec<Subject>
 }
 
@@ -17,123 +20,200 @@ pub(crate) struct Subject {
 }
 
 impl Subject {
-    pub fn to_string(&self) -> String {
-        format!("{}:\nПосещаемость: {}\nТворческий: {}\nКонтрольный: {}\nЭкз/зачет: {}\nВсего: {}", 
-            &self.name, 
-            &self.attendance, 
-            &self.creative,
-            &self.control, 
-            &self.test,
-            &self.attendance + &self.control + &self.creative + &self.test
-        )
+    pub(crate) fn components(&self) -> [(&'static str, f32); 4] {
+        [
+            ("attendance", self.attendance),
+            ("creative", self.creative),
+            ("control", self.control),
+            ("test", self.test)
+        ]
     }
 }
 
-fn parse_rating_value(subject_elem: &ElementRef, item_selector: &Selector, value_selector: &Selector) -> Option<f32> {
-    let item_elem: Vec<ElementRef> = subject_elem.select(&item_selector).collect();
-    if item_elem.len() != 1 { warn!("Couldn't find value of the subject"); return None; }
-
-    let item_value: Vec<ElementRef> = item_elem[0].select(&value_selector).collect();
-    if item_value.len() != 1 { warn!("Couldn't find value of the subject"); return None; }
+pub(crate) fn component_name(component: &str) -> &'static str {
+    match component {
+        "attendance" => "Посещаемость",
+        "creative" => "Творческий",
+        "control" => "Контрольный",
+        "test" => "Экз/зачет",
+        _ => "?"
+    }
+}
 
-    let value = item_value[0].inner_html().trim().parse::<f32>();
-    if value.is_err() { warn!("Error parsing value value"); return None; } 
-    Some(value.unwrap())
+// How a change of the component reads in notifications, "+2 за посещение".
+pub(crate) fn component_change(component: &str) -> &'static str {
+    match component {
+        "attendance" => "за посещение",
+        "creative" => "по творческому",
+        "control" => "за контрольный",
+        "test" => "за экз/тест",
+        _ => "?"
+    }
 }
 
-fn parse_test_value(subject_elem: &ElementRef, item_selector: &Selector) -> Option<f32> {
-    let item_elem: Vec<ElementRef> = subject_elem.select(&item_selector).collect();
-    if item_elem.len() != 1 { warn!("Couldn't find attandance of the subject"); return None; }
+impl std::fmt::Display for Subject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:\nПосещаемость: {}\nТворческий: {}\nКонтрольный: {}\nЭкз/зачет: {}\nВсего: {}", 
+            self.name, 
+            self.attendance, 
+            self.creative,
+            self.control, 
+            self.test,
+            self.attendance + self.control + self.creative + self.test
+        )
+    }
+}
 
-    let value = item_elem[0].inner_html().trim().parse::<f32>();
-    if value.is_err() { warn!("Error parsing attendance value"); return None; } 
-    Some(value.unwrap())
+#[derive(Debug)]
+pub(crate) enum RatingError {
+    BadCredentials,
+    Network(reqwest::Error),
+    PortalLayoutChanged { selector: &'static str },
+    ParseValue { subject: String, field: &'static str, raw: String },
+    SemesterUnavailable
 }
 
-pub(crate) async fn get_rating(user: User) -> Option<Rating> {
-    let params = [
-        ("AUTH_FORM", "Y"), 
-        ("TYPE", "AUTH"), 
-        ("backurl", "/index.php"), 
-        ("USER_LOGIN", &user.username),
-        ("USER_PASSWORD", &user.pwd),
-        ("Login", "Войти"),
-        ("login", "yes"),
-        ("semester", &format!("{}-й семестр", user.semester))
-    ];
-
-    let client = reqwest::ClientBuilder::new()
-    .danger_accept_invalid_certs(true)
-    .cookie_store(true)
-    .build().unwrap();
-
-    let title_selector = Selector::parse("title").unwrap();
-
-    let auth_res = client.post("https://student.rea.ru/index.php")
-    .form(&params[0..5])
-    .query(&params[6..7])
-    .send()
-    .await;
-    if auth_res.is_err() {
-        warn!("Reqwest error while sending rea auth request ({})", auth_res.err().unwrap().to_string());
-        return None;
+impl std::fmt::Display for RatingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RatingError::BadCredentials => write!(f, "bad credentials"),
+            RatingError::Network(err) => write!(f, "network error ({})", err),
+            RatingError::PortalLayoutChanged { selector } => write!(f, "portal layout changed, nothing matches `{}`", selector),
+            RatingError::ParseValue { subject, field, raw } => write!(f, "couldn't parse {} of {} from {:?}", field, subject, raw),
+            RatingError::SemesterUnavailable => write!(f, "semester is unavailable")
+        }
     }
+}
 
-    let auth_res_text = auth_res.unwrap().text().await;
-    if auth_res_text.is_err() {
-        warn!("Couldn't get auth request text Err({})", auth_res_text.err().unwrap().to_string());
-        return None;
+impl std::error::Error for RatingError {}
+
+impl From<reqwest::Error> for RatingError {
+    fn from(err: reqwest::Error) -> Self {
+        RatingError::Network(err)
     }
+}
+
+// A university portal the rating is scraped from.
+#[async_trait::async_trait]
+pub(crate) trait RatingSource: Send + Sync {
+    // Makes sure there is a logged in session for the user.
+    async fn authenticate(&self, user: &User, pwd: &str) -> Result<(), RatingError>;
+    // Returns the raw rating page of the semester (or the one the portal picks without it),
+    // the user has to be authenticated.
+    async fn fetch_semester(&self, user: &User, semester: Option<u8>) -> Result<String, RatingError>;
+    fn parse_subjects(&self, page: &str) -> Result<Vec<Subject>, RatingError>;
+    // Semesters that can be picked on the rating page.
+    fn parse_semesters(&self, page: &str) -> Vec<u8>;
+    // Drops whatever is kept of the user's logged in session.
+    fn forget(&self, user: &User);
+}
+
+// Only semesters in `semesters` are picked for the user or reported as the latest one,
+// the portal may list more than users are allowed to switch to.
+pub(crate) async fn get_rating(source: &dyn RatingSource, user: User, cipher: &crypto::Cipher, semesters: &RangeInclusive<u8>) -> Result<Rating, RatingError> {
+    // A password that doesn't decrypt was saved under another key,
+    // the user has to enter it again just like after changing it on the portal.
+    let pwd = cipher.decrypt(&user.pwd).ok_or(RatingError::BadCredentials)?;
 
-    {
-        let auth_html = Html::parse_document(&auth_res_text.unwrap());
-        let titles: Vec<ElementRef> = auth_html.select(&title_selector).collect();
-        if titles.len() != 1 || titles[0].inner_html() != "Информация об обучающемся" {
-            warn!("no auth");
-            return None;
+    source.authenticate(&user, &pwd).await?;
+
+    let latest_semester = |page: &str| source.parse_semesters(page).into_iter().filter(|semester| semesters.contains(semester)).max();
+
+    // Users who haven't picked a semester get the latest one
+    let semester = match user.semester {
+        0 => {
+            let page = source.fetch_semester(&user, None).await?;
+            latest_semester(&page).ok_or(RatingError::SemesterUnavailable)?
         }
+        semester => semester
     };
 
-    let rating_res = client.get("https://student.rea.ru/rating/index.php")
-    .query(&params[7..8])
-    .send()
-    .await;
-    if rating_res.is_err() {
-        warn!("Reqwest error while sending rea auth request ({})", rating_res.err().unwrap().to_string());
-        return None;
+    let page = source.fetch_semester(&user, Some(semester)).await?;
+    Ok(Rating { 
+        subjects: source.parse_subjects(&page)?, 
+        semester, 
+        latest_semester: latest_semester(&page), 
+        user 
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockSource;
+    use crate::testing::{test_cipher, test_user};
+
+    const RATING_PAGE: &str = include_str!("../tests/fixtures/rating.html");
+    const SEMESTERS: RangeInclusive<u8> = 1..=8;
+
+    #[tokio::test]
+    async fn get_rating_reads_requested_semester() {
+        let cipher = test_cipher();
+        let source = MockSource::default().with_password("secret").with_semester(7, RATING_PAGE);
+
+        let rating = get_rating(&source, test_user(&cipher, "secret", 7), &cipher, &SEMESTERS).await.unwrap();
+        assert_eq!(rating.user.id, 1);
+        assert_eq!(rating.semester, 7);
+        assert_eq!(rating.subjects.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn get_rating_reports_wrong_password() {
+        let cipher = test_cipher();
+        let source = MockSource::default().with_password("changed").with_semester(7, RATING_PAGE);
+
+        let res = get_rating(&source, test_user(&cipher, "secret", 7), &cipher, &SEMESTERS).await;
+        assert!(matches!(res, Err(RatingError::BadCredentials)));
     }
-    let rating_res_text = rating_res.unwrap().text().await;
-    if rating_res_text.is_err() {
-        warn!("Couldn't get rating request text Err({})", rating_res_text.err().unwrap().to_string());
-        return None;
+
+    #[tokio::test]
+    async fn get_rating_reports_password_under_another_key() {
+        let cipher = test_cipher();
+        let source = MockSource::default().with_semester(7, RATING_PAGE);
+
+        let res = get_rating(&source, test_user(&test_cipher(), "secret", 7), &cipher, &SEMESTERS).await;
+        assert!(matches!(res, Err(RatingError::BadCredentials)));
     }
 
-    let rating_html = Html::parse_document(&rating_res_text.unwrap());
-
-    let subjects_selector = Selector::parse("div.es-rating__line-parent").unwrap();
-    let name_selector = Selector::parse("div.es-rating__discipline").unwrap();
-
-    let attendance_selector = Selector::parse("div.es-rating__attendance").unwrap();
-    let control_selector = Selector::parse("div.es-rating__control").unwrap();
-    let creative_selector = Selector::parse("div.es-rating__creative").unwrap();
-    let test_selector = Selector::parse("div.es-rating__form").unwrap();
-    
-    let number_selector = Selector::parse("a").unwrap();
-
-    let mut rating = Rating {user, subjects: vec![] };
-    for subject_elem in rating_html.select(&subjects_selector) {
-        let subject_name: Vec<ElementRef> = subject_elem.select(&name_selector).collect();
-        if subject_name.len() != 1 { warn!("Couldn't find name of the subject"); return None; }
-
-        rating.subjects.push(
-            Subject { 
-                name: subject_name[0].inner_html().trim().to_string(), 
-                attendance: parse_rating_value(&subject_elem, &attendance_selector, &number_selector)?,
-                control: parse_rating_value(&subject_elem, &control_selector, &number_selector)?,
-                creative: parse_rating_value(&subject_elem, &creative_selector, &number_selector)?,
-                test: parse_test_value(&subject_elem, &test_selector)?
-            }
-        );
+    #[tokio::test]
+    async fn get_rating_reports_missing_semester() {
+        let cipher = test_cipher();
+        let source = MockSource::default().with_semester(7, RATING_PAGE);
+
+        let res = get_rating(&source, test_user(&cipher, "secret", 8), &cipher, &SEMESTERS).await;
+        assert!(matches!(res, Err(RatingError::SemesterUnavailable)));
     }
-    Some(rating)
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn get_rating_picks_latest_semester_when_unset() {
+        let cipher = test_cipher();
+        let source = MockSource::default().with_semester(6, RATING_PAGE).with_semester(7, RATING_PAGE);
+
+        let rating = get_rating(&source, test_user(&cipher, "secret", 0), &cipher, &SEMESTERS).await.unwrap();
+        assert_eq!(rating.semester, 7);
+        assert_eq!(rating.latest_semester, Some(7));
+    }
+
+    #[tokio::test]
+    async fn get_rating_reports_newer_semester() {
+        let cipher = test_cipher();
+        let source = MockSource::default().with_semester(6, RATING_PAGE).with_semester(7, RATING_PAGE);
+
+        let rating = get_rating(&source, test_user(&cipher, "secret", 6), &cipher, &SEMESTERS).await.unwrap();
+        assert_eq!(rating.semester, 6);
+        assert_eq!(rating.latest_semester, Some(7));
+    }
+
+    #[tokio::test]
+    async fn get_rating_ignores_semesters_out_of_range() {
+        let cipher = test_cipher();
+        let source = MockSource::default().with_semester(7, RATING_PAGE).with_semester(9, RATING_PAGE);
+
+        let rating = get_rating(&source, test_user(&cipher, "secret", 0), &cipher, &SEMESTERS).await.unwrap();
+        assert_eq!(rating.semester, 7);
+        assert_eq!(rating.latest_semester, Some(7));
+
+        let res = get_rating(&source, test_user(&cipher, "secret", 0), &cipher, &(10..=12)).await;
+        assert!(matches!(res, Err(RatingError::SemesterUnavailable)));
+    }
+}
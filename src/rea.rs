@@ -0,0 +1,444 @@
+use crate::db::User;
+use crate::limit::RateLimiter;
+use crate::rating::{RatingError, RatingSource, Subject};
+use scraper::{Html, Selector, ElementRef};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub(crate) const DEFAULT_BASE_URL: &str = "https://student.rea.ru";
+
+const SUBJECTS_SELECTOR: &str = "div.es-rating__line-parent";
+const NAME_SELECTOR: &str = "div.es-rating__discipline";
+const ATTENDANCE_SELECTOR: &str = "div.es-rating__attendance";
+const CONTROL_SELECTOR: &str = "div.es-rating__control";
+const CREATIVE_SELECTOR: &str = "div.es-rating__creative";
+const TEST_SELECTOR: &str = "div.es-rating__form";
+const NUMBER_SELECTOR: &str = "a";
+const TITLE_SELECTOR: &str = "title";
+// Unverified: the selectors below have only been checked against the synthetic pages in tests/fixtures,
+// not against captures of the real portal (see tests/fixtures/README.md).
+const SEMESTERS_SELECTOR: &str = "select[name=semester] option";
+// Parts of the rating page that are there even for a semester without subjects
+const RATING_CONTAINER_SELECTOR: &str = "div.es-rating, select[name=semester]";
+// The portal answers a rejected login with the same form again
+const LOGIN_FORM_SELECTOR: &str = "form[name=system_auth_form]";
+
+fn select_one<'a>(elem: &ElementRef<'a>, selector: &'static str) -> Result<ElementRef<'a>, RatingError> {
+    let parsed = Selector::parse(selector).unwrap();
+
+    let found: Vec<ElementRef> = elem.select(&parsed).collect();
+    if found.len() != 1 { return Err(RatingError::PortalLayoutChanged { selector }); }
+    Ok(found[0])
+}
+
+fn parse_value(subject: &str, field: &'static str, raw: String) -> Result<f32, RatingError> {
+    let value = raw.trim().replace(',', ".");
+    // Cells stay empty until something gets graded
+    if value.is_empty() { return Ok(0.0); }
+
+    value.parse::<f32>().map_err(|_| RatingError::ParseValue { subject: subject.to_string(), field, raw })
+}
+
+fn parse_rating_value(subject_elem: &ElementRef, subject: &str, field: &'static str, item_selector: &'static str) -> Result<f32, RatingError> {
+    let item_elem = select_one(subject_elem, item_selector)?;
+    let item_value = select_one(&item_elem, NUMBER_SELECTOR)?;
+
+    parse_value(subject, field, item_value.inner_html())
+}
+
+fn parse_test_value(subject_elem: &ElementRef, subject: &str, field: &'static str, item_selector: &'static str) -> Result<f32, RatingError> {
+    let item_elem = select_one(subject_elem, item_selector)?;
+
+    parse_value(subject, field, item_elem.inner_html())
+}
+
+struct Session {
+    username: String,
+    pwd: String,
+    client: reqwest::Client
+}
+
+// Logged in portal clients (with their cookie jars) by user id.
+#[derive(Clone, Default)]
+pub(crate) struct Sessions(Arc<Mutex<HashMap<i64, Session>>>);
+
+impl Sessions {
+    fn client_for(&self, user: &User) -> reqwest::Client {
+        let mut sessions = self.0.lock().unwrap();
+        if let Some(session) = sessions.get(&user.id) {
+            if session.username == user.username && session.pwd == user.pwd {
+                return session.client.clone();
+            }
+        }
+
+        let client = reqwest::ClientBuilder::new()
+        .danger_accept_invalid_certs(true)
+        .cookie_store(true)
+        .build().unwrap();
+
+        sessions.insert(user.id, Session { username: user.username.clone(), pwd: user.pwd.clone(), client: client.clone() });
+        client
+    }
+
+    pub(crate) fn forget(&self, user_id: i64) {
+        self.0.lock().unwrap().remove(&user_id);
+    }
+}
+
+async fn fetch_text(limiter: &RateLimiter, request: reqwest::RequestBuilder) -> Result<String, RatingError> {
+    limiter.wait().await;
+    Ok(request.send().await?.error_for_status()?.text().await?)
+}
+
+fn is_authenticated(page: &str) -> bool {
+    let title_selector = Selector::parse(TITLE_SELECTOR).unwrap();
+
+    let html = Html::parse_document(page);
+    let titles: Vec<ElementRef> = html.select(&title_selector).collect();
+    titles.len() == 1 && titles[0].inner_html() == "Информация об обучающемся"
+}
+
+fn is_login_page(page: &str) -> bool {
+    let form_selector = Selector::parse(LOGIN_FORM_SELECTOR).unwrap();
+    Html::parse_document(page).select(&form_selector).next().is_some()
+}
+
+pub(crate) fn parse_subjects(page: &str) -> Result<Vec<Subject>, RatingError> {
+    let rating_html = Html::parse_document(page);
+    let subjects_selector = Selector::parse(SUBJECTS_SELECTOR).unwrap();
+
+    let mut subjects = vec![];
+    for subject_elem in rating_html.select(&subjects_selector) {
+        let name = select_one(&subject_elem, NAME_SELECTOR)?.inner_html().trim().to_string();
+
+        subjects.push(
+            Subject { 
+                attendance: parse_rating_value(&subject_elem, &name, "attendance", ATTENDANCE_SELECTOR)?,
+                control: parse_rating_value(&subject_elem, &name, "control", CONTROL_SELECTOR)?,
+                creative: parse_rating_value(&subject_elem, &name, "creative", CREATIVE_SELECTOR)?,
+                test: parse_test_value(&subject_elem, &name, "test", TEST_SELECTOR)?,
+                name
+            }
+        );
+    }
+
+    // No subjects on a rating page means the semester is empty, on any other page
+    // (a renamed container, the login form of an expired session) that the layout is not what we expect
+    if subjects.is_empty() {
+        let container_selector = Selector::parse(RATING_CONTAINER_SELECTOR).unwrap();
+        if rating_html.select(&container_selector).next().is_some() {
+            return Err(RatingError::SemesterUnavailable);
+        }
+        return Err(RatingError::PortalLayoutChanged { selector: SUBJECTS_SELECTOR });
+    }
+    Ok(subjects)
+}
+
+// Options look like "7-й семестр".
+pub(crate) fn parse_semesters(page: &str) -> Vec<u8> {
+    let html = Html::parse_document(page);
+    let option_selector = Selector::parse(SEMESTERS_SELECTOR).unwrap();
+
+    let mut semesters: Vec<u8> = html.select(&option_selector)
+        .filter_map(|option| option.inner_html().trim().split('-').next().and_then(|num| num.parse::<u8>().ok()))
+        .collect();
+    semesters.sort_unstable();
+    semesters.dedup();
+    semesters
+}
+
+// The student portal of Plekhanov Russian University of Economics.
+pub(crate) struct ReaPortal {
+    base_url: String,
+    sessions: Sessions,
+    limiter: RateLimiter
+}
+
+impl ReaPortal {
+    pub(crate) fn new(base_url: &str, limiter: RateLimiter) -> ReaPortal {
+        ReaPortal { base_url: base_url.trim_end_matches('/').to_string(), sessions: Sessions::default(), limiter }
+    }
+}
+
+#[async_trait::async_trait]
+impl RatingSource for ReaPortal {
+    async fn authenticate(&self, user: &User, pwd: &str) -> Result<(), RatingError> {
+        let params = [
+            ("AUTH_FORM", "Y"), 
+            ("TYPE", "AUTH"), 
+            ("backurl", "/index.php"), 
+            ("USER_LOGIN", &user.username),
+            ("USER_PASSWORD", pwd),
+            ("login", "yes")
+        ];
+
+        let client = self.sessions.client_for(user);
+
+        // The portal keeps us logged in for a while, so the stored cookies are tried first
+        // and the login form is only posted when they don't get us to the student page.
+        let index_text = fetch_text(&self.limiter, client.get(format!("{}/index.php", self.base_url))).await?;
+        if is_authenticated(&index_text) {
+            return Ok(());
+        }
+
+        let auth_text = fetch_text(&self.limiter, client.post(format!("{}/index.php", self.base_url))
+            .form(&params[0..5])
+            .query(&params[5..6])).await?;
+
+        if is_authenticated(&auth_text) {
+            return Ok(());
+        }
+
+        // Only the login form coming back means the password is wrong, a maintenance page
+        // or anything else unexpected mustn't count against the user's credentials
+        self.sessions.forget(user.id);
+        if is_login_page(&auth_text) {
+            return Err(RatingError::BadCredentials);
+        }
+        Err(RatingError::PortalLayoutChanged { selector: TITLE_SELECTOR })
+    }
+
+    async fn fetch_semester(&self, user: &User, semester: Option<u8>) -> Result<String, RatingError> {
+        let client = self.sessions.client_for(user);
+
+        let mut request = client.get(format!("{}/rating/index.php", self.base_url));
+        if let Some(semester) = semester {
+            request = request.query(&[("semester", format!("{}-й семестр", semester))]);
+        }
+
+        let rating_res_text = fetch_text(&self.limiter, request).await;
+        if rating_res_text.is_err() {
+            self.sessions.forget(user.id);
+        }
+        rating_res_text
+    }
+
+    fn parse_subjects(&self, page: &str) -> Result<Vec<Subject>, RatingError> {
+        parse_subjects(page)
+    }
+
+    fn parse_semesters(&self, page: &str) -> Vec<u8> {
+        parse_semesters(page)
+    }
+
+    fn forget(&self, user: &User) {
+        self.sessions.forget(user.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_cipher, test_user};
+    use crate::rating;
+    use wiremock::matchers::{body_string_contains, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const LOGIN_PAGE: &str = include_str!("../tests/fixtures/login.html");
+    const STUDENT_PAGE: &str = include_str!("../tests/fixtures/student.html");
+    const RATING_PAGE: &str = include_str!("../tests/fixtures/rating.html");
+    const EMPTY_RATING_PAGE: &str = include_str!("../tests/fixtures/rating_empty.html");
+    const LAYOUT_CHANGED_PAGE: &str = include_str!("../tests/fixtures/rating_layout_changed.html");
+
+    fn parse_line(line: &str, parse: impl Fn(&ElementRef) -> Result<f32, RatingError>) -> Result<f32, RatingError> {
+        let html = Html::parse_fragment(&format!("<div class=\"es-rating__line-parent\">{}</div>", line));
+        let subject_elem = html.select(&Selector::parse(SUBJECTS_SELECTOR).unwrap()).next().unwrap();
+        parse(&subject_elem)
+    }
+
+    fn rating_value(line: &str) -> Result<f32, RatingError> {
+        parse_line(line, |elem| parse_rating_value(elem, "Экономика", "attendance", ATTENDANCE_SELECTOR))
+    }
+
+    fn test_value(line: &str) -> Result<f32, RatingError> {
+        parse_line(line, |elem| parse_test_value(elem, "Экономика", "test", TEST_SELECTOR))
+    }
+
+    #[test]
+    fn parse_rating_value_reads_link_text() {
+        assert_eq!(rating_value(r#"<div class="es-rating__attendance"><a> 12 </a></div>"#).unwrap(), 12.0);
+        assert_eq!(rating_value(r#"<div class="es-rating__attendance"><a>12.5</a></div>"#).unwrap(), 12.5);
+    }
+
+    #[test]
+    fn parse_rating_value_accepts_decimal_comma() {
+        assert_eq!(rating_value(r#"<div class="es-rating__attendance"><a>7,25</a></div>"#).unwrap(), 7.25);
+    }
+
+    #[test]
+    fn parse_rating_value_treats_empty_cell_as_zero() {
+        assert_eq!(rating_value(r#"<div class="es-rating__attendance"><a></a></div>"#).unwrap(), 0.0);
+        assert_eq!(rating_value(r#"<div class="es-rating__attendance"><a>  </a></div>"#).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn parse_rating_value_reports_missing_cell() {
+        let res = rating_value(r#"<div class="es-rating__control"><a>1</a></div>"#);
+        assert!(matches!(res, Err(RatingError::PortalLayoutChanged { selector: ATTENDANCE_SELECTOR })));
+
+        let res = rating_value(r#"<div class="es-rating__attendance">1</div>"#);
+        assert!(matches!(res, Err(RatingError::PortalLayoutChanged { selector: NUMBER_SELECTOR })));
+    }
+
+    #[test]
+    fn parse_rating_value_reports_garbage() {
+        let res = rating_value(r#"<div class="es-rating__attendance"><a>н/я</a></div>"#);
+        match res {
+            Err(RatingError::ParseValue { subject, field, raw }) => {
+                assert_eq!(subject, "Экономика");
+                assert_eq!(field, "attendance");
+                assert_eq!(raw, "н/я");
+            }
+            _ => panic!("expected ParseValue, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn parse_test_value_reads_cell_text() {
+        assert_eq!(test_value(r#"<div class="es-rating__form"> 30 </div>"#).unwrap(), 30.0);
+        assert_eq!(test_value(r#"<div class="es-rating__form">22,5</div>"#).unwrap(), 22.5);
+        assert_eq!(test_value(r#"<div class="es-rating__form"></div>"#).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn parse_test_value_reports_missing_cell() {
+        let res = test_value(r#"<div class="es-rating__exam">30</div>"#);
+        assert!(matches!(res, Err(RatingError::PortalLayoutChanged { selector: TEST_SELECTOR })));
+    }
+
+    #[test]
+    fn parse_subjects_reads_rating_page() {
+        let subjects = parse_subjects(RATING_PAGE).unwrap();
+        assert_eq!(subjects.len(), 3);
+
+        assert_eq!(subjects[0].name, "Экономическая теория");
+        assert_eq!(subjects[0].components(), [("attendance", 10.0), ("creative", 7.0), ("control", 12.5), ("test", 30.0)]);
+
+        assert_eq!(subjects[1].name, "Иностранный язык");
+        assert_eq!(subjects[1].components(), [("attendance", 8.5), ("creative", 0.0), ("control", 0.0), ("test", 0.0)]);
+    }
+
+    #[test]
+    fn parse_subjects_without_subjects_is_unavailable_semester() {
+        assert!(matches!(parse_subjects(EMPTY_RATING_PAGE), Err(RatingError::SemesterUnavailable)));
+    }
+
+    #[test]
+    fn parse_subjects_reports_changed_layout() {
+        assert!(matches!(parse_subjects(LAYOUT_CHANGED_PAGE), Err(RatingError::PortalLayoutChanged { selector: ATTENDANCE_SELECTOR })));
+
+        let renamed = RATING_PAGE.replace("es-rating", "es-grades").replace("name=\"semester\"", "name=\"term\"");
+        assert!(matches!(parse_subjects(&renamed), Err(RatingError::PortalLayoutChanged { selector: SUBJECTS_SELECTOR })));
+    }
+
+    #[test]
+    fn parse_subjects_on_login_page_is_changed_layout() {
+        assert!(matches!(parse_subjects(LOGIN_PAGE), Err(RatingError::PortalLayoutChanged { selector: SUBJECTS_SELECTOR })));
+    }
+
+    #[test]
+    fn parse_semesters_reads_selector() {
+        assert_eq!(parse_semesters(RATING_PAGE), vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(parse_semesters(EMPTY_RATING_PAGE), vec![1, 2]);
+        assert!(parse_semesters(LAYOUT_CHANGED_PAGE).is_empty());
+    }
+
+    #[test]
+    fn is_authenticated_checks_title() {
+        assert!(is_authenticated(STUDENT_PAGE));
+        assert!(!is_authenticated(LOGIN_PAGE));
+    }
+
+    // Emulates the portal: the student page needs the session cookie which is handed out
+    // for the right password, the rating page is served for the 7th semester.
+    async fn mock_portal() -> MockServer {
+        let server = MockServer::start().await;
+
+        Mock::given(method("GET")).and(path("/index.php")).and(header("cookie", "PHPSESSID=session"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(STUDENT_PAGE))
+            .mount(&server).await;
+        Mock::given(method("GET")).and(path("/index.php"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(LOGIN_PAGE))
+            .mount(&server).await;
+        Mock::given(method("POST")).and(path("/index.php")).and(query_param("login", "yes"))
+            .and(body_string_contains("USER_PASSWORD=secret"))
+            .respond_with(ResponseTemplate::new(200)
+                .insert_header("set-cookie", "PHPSESSID=session; path=/")
+                .set_body_string(STUDENT_PAGE))
+            .mount(&server).await;
+        Mock::given(method("POST")).and(path("/index.php"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(LOGIN_PAGE))
+            .mount(&server).await;
+        Mock::given(method("GET")).and(path("/rating/index.php")).and(query_param("semester", "7-й семестр"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(RATING_PAGE))
+            .mount(&server).await;
+
+        server
+    }
+
+    #[tokio::test]
+    async fn get_rating_logs_in_once_and_reuses_session() {
+        let server = mock_portal().await;
+        let cipher = test_cipher();
+        let portal = ReaPortal::new(&server.uri(), RateLimiter::new(100));
+        let user = test_user(&cipher, "secret", 7);
+
+        for _ in 0..2 {
+            let rating = rating::get_rating(&portal, user.clone(), &cipher, &(1..=8)).await.unwrap();
+            assert_eq!(rating.subjects.len(), 3);
+            assert_eq!(rating.subjects[2].name, "Физическая культура");
+        }
+
+        let logins = server.received_requests().await.unwrap().into_iter().filter(|req| req.method == wiremock::http::Method::Post).count();
+        assert_eq!(logins, 1);
+
+        // A forgotten session has to log in again
+        portal.forget(&user);
+        rating::get_rating(&portal, user.clone(), &cipher, &(1..=8)).await.unwrap();
+        let logins = server.received_requests().await.unwrap().into_iter().filter(|req| req.method == wiremock::http::Method::Post).count();
+        assert_eq!(logins, 2);
+    }
+
+    #[tokio::test]
+    async fn get_rating_reports_bad_credentials() {
+        let server = mock_portal().await;
+        let cipher = test_cipher();
+
+        let portal = ReaPortal::new(&server.uri(), RateLimiter::new(100));
+
+        let res = rating::get_rating(&portal, test_user(&cipher, "wrong", 7), &cipher, &(1..=8)).await;
+        assert!(matches!(res, Err(RatingError::BadCredentials)));
+    }
+
+    #[tokio::test]
+    async fn get_rating_reports_portal_outage_as_network_error() {
+        let server = MockServer::start().await;
+        Mock::given(wiremock::matchers::any())
+            .respond_with(ResponseTemplate::new(503).set_body_string("<html><title>Технические работы</title></html>"))
+            .mount(&server).await;
+        let cipher = test_cipher();
+        let portal = ReaPortal::new(&server.uri(), RateLimiter::new(100));
+
+        let res = rating::get_rating(&portal, test_user(&cipher, "secret", 7), &cipher, &(1..=8)).await;
+        assert!(matches!(res, Err(RatingError::Network(_))));
+    }
+
+    #[tokio::test]
+    async fn get_rating_reports_unexpected_page_as_changed_layout() {
+        let server = MockServer::start().await;
+        Mock::given(wiremock::matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_body_string("<html><title>Технические работы</title></html>"))
+            .mount(&server).await;
+        let cipher = test_cipher();
+        let portal = ReaPortal::new(&server.uri(), RateLimiter::new(100));
+
+        let res = rating::get_rating(&portal, test_user(&cipher, "secret", 7), &cipher, &(1..=8)).await;
+        assert!(matches!(res, Err(RatingError::PortalLayoutChanged { selector: TITLE_SELECTOR })));
+    }
+
+    #[test]
+    fn is_login_page_checks_form() {
+        assert!(is_login_page(LOGIN_PAGE));
+        assert!(!is_login_page(STUDENT_PAGE));
+    }
+}
@@ -0,0 +1,20 @@
+// Fixtures shared by the tests of several modules.
+use crate::crypto::Cipher;
+use crate::db::User;
+
+pub(crate) fn test_cipher() -> Cipher {
+    Cipher::from_base64(&Cipher::generate_key()).unwrap()
+}
+
+// A logged in user as it comes from the database, semester 0 is not picked yet.
+pub(crate) fn test_user(cipher: &Cipher, pwd: &str, semester: u8) -> User {
+    User {
+        id: 1,
+        chat_id: 1,
+        username: "student".to_string(),
+        pwd: cipher.encrypt(pwd),
+        semester,
+        auth_failures: 0,
+        auth_failure_notified: false
+    }
+}
@@ -1,29 +1,161 @@
-use teloxide::requests::Requester;
+use teloxide::adaptors::Throttle;
+use teloxide::requests::{Request, Requester};
+use teloxide::payloads::SendMessageSetters;
+use teloxide::{ApiError, Bot, RequestError};
 
 use crate::db::User;
-use teloxide::types::ChatId;
+use teloxide::types::{ChatId, ParseMode};
 
-pub(crate) async fn get_user_string(bot: teloxide::Bot, user: User) -> String {
-    let chat = bot.get_chat( ChatId { 0: user.chat_id }).await;
+// The bot everything sends through, it queues requests to stay within Telegram's limits
+// (about 30 messages a second overall and one a second per chat).
+pub(crate) type TgBot = Throttle<Bot>;
 
-    if chat.is_ok() {
-        let chat = chat.unwrap();
-        let username = chat.username();
-        if username.is_some() {
-            return format!("User {} [login: {}, sem: {}]", user.id, username.unwrap(), user.semester);
+// Telegram's limit, counted in UTF-16 code units
+const MAX_MESSAGE_LEN: usize = 4096;
+
+// Splits the text into messages Telegram accepts, between lines where possible.
+pub(crate) fn split_message(text: &str, max_len: usize) -> Vec<String> {
+    let mut parts: Vec<String> = vec![];
+    let mut current = String::new();
+    let mut current_len = 0;
+    let mut flush = |current: &mut String, current_len: &mut usize| {
+        let part = current.trim_end_matches('\n');
+        if !part.is_empty() {
+            parts.push(part.to_string());
+        }
+        current.clear();
+        *current_len = 0;
+    };
+
+    for line in text.split_inclusive('\n') {
+        // The line break is dropped if the line ends up last in a message
+        let line_len = line.trim_end_matches('\n').encode_utf16().count();
+        if current_len + line_len > max_len {
+            flush(&mut current, &mut current_len);
+        }
+
+        if line_len <= max_len {
+            current.push_str(line);
+            current_len += line.encode_utf16().count();
+            continue;
+        }
+
+        // A single line that doesn't fit anywhere is cut wherever
+        for ch in line.chars() {
+            if current_len + ch.len_utf16() > max_len {
+                flush(&mut current, &mut current_len);
+            }
+            current.push(ch);
+            current_len += ch.len_utf16();
+        }
+    }
+    flush(&mut current, &mut current_len);
+
+    parts
+}
+
+// Plain text of any length, long texts go out as several messages.
+pub(crate) async fn send_long(bot: &TgBot, chat_id: ChatId, text: &str) -> Result<(), RequestError> {
+    for part in split_message(text, MAX_MESSAGE_LEN) {
+        bot.send_message(chat_id, part).await?;
+    }
+    Ok(())
+}
+
+// How many times a single send waits out "Too Many Requests" before giving up
+const MAX_RETRY_AFTER: u32 = 3;
+
+#[derive(Debug)]
+pub(crate) enum SendError {
+    // The user blocked the bot or is gone, sending again won't help
+    Unreachable(RequestError),
+    Failed(RequestError)
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Unreachable(err) => write!(f, "chat is unreachable ({})", err),
+            SendError::Failed(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+// Long texts are split between lines, which keeps the markup of the notifications intact.
+pub(crate) async fn send_markdown(bot: &TgBot, chat_id: i64, text: &str) -> Result<(), SendError> {
+    for part in split_message(text, MAX_MESSAGE_LEN) {
+        send_markdown_part(bot, chat_id, &part).await?;
+    }
+    Ok(())
+}
+
+async fn send_markdown_part(bot: &TgBot, chat_id: i64, text: &str) -> Result<(), SendError> {
+    let mut waits = 0;
+    loop {
+        match bot.send_message(ChatId(chat_id), text).parse_mode(ParseMode::MarkdownV2).send().await {
+            Ok(_) => return Ok(()),
+            Err(RequestError::RetryAfter(delay)) if waits < MAX_RETRY_AFTER => {
+                log::info!("Telegram asked to wait {:?} before sending to chat {}", delay, chat_id);
+                tokio::time::sleep(delay).await;
+                waits += 1;
+            }
+            Err(err @ RequestError::Api(ApiError::BotBlocked | ApiError::UserDeactivated | ApiError::ChatNotFound | ApiError::BotKicked)) => {
+                return Err(SendError::Unreachable(err));
+            }
+            Err(err) => return Err(SendError::Failed(err))
+        }
+    }
+}
+
+pub(crate) async fn get_user_string(bot: TgBot, user: User) -> String {
+    let chat = bot.get_chat(ChatId(user.chat_id)).await;
+
+    if let Ok(chat) = chat {
+        if let Some(username) = chat.username() {
+            return format!("User {} [login: {}, sem: {}]", user.id, username, user.semester);
         }
 
         let first_name = chat.first_name();
         let last_name = chat.last_name();
 
-        if first_name.is_some() && last_name.is_some() {
-            return format!("User {} [login: {} {}, sem: {}]", user.id, first_name.unwrap(), last_name.unwrap(), user.semester);
+        if let (Some(first_name), Some(last_name)) = (first_name, last_name) {
+            return format!("User {} [login: {} {}, sem: {}]", user.id, first_name, last_name, user.semester);
         }
 
-        if first_name.is_some() && last_name.is_none() {
-            return format!("User {} [login: {}, sem: {}]", user.id, first_name.unwrap(), user.semester);
+        if let Some(first_name) = first_name {
+            return format!("User {} [login: {}, sem: {}]", user.id, first_name, user.semester);
         }
     }
 
     format!("User {} [login: {}, sem: {}]", user.id, user.username, user.semester)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_messages_stay_whole() {
+        assert_eq!(split_message("Экономика:\nВсего: 10", 4096), vec!["Экономика:\nВсего: 10"]);
+        assert!(split_message("", 4096).is_empty());
+    }
+
+    #[test]
+    fn long_messages_split_between_lines() {
+        let text = ["aaaa", "bbbb", "cccc"].join("\n");
+        assert_eq!(split_message(&text, 9), vec!["aaaa\nbbbb", "cccc"]);
+
+        let subjects = vec!["Экономика: 10"; 1000].join("\n\n");
+        let parts = split_message(&subjects, MAX_MESSAGE_LEN);
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|part| part.encode_utf16().count() <= MAX_MESSAGE_LEN));
+        assert!(parts.iter().all(|part| part.starts_with("Экономика") && part.ends_with("10")));
+    }
+
+    #[test]
+    fn long_lines_are_cut() {
+        assert_eq!(split_message("abcdefg", 3), vec!["abc", "def", "g"]);
+        // Emoji take two UTF-16 code units
+        assert_eq!(split_message("😏😏😏", 4), vec!["😏😏", "😏"]);
+    }
+}